@@ -1,3 +1,13 @@
 fn main() {
-    tonic_build::compile_protos("src/proto/lakh.proto").unwrap();
+    tonic_build::configure()
+        // jobs are sent one at a time, boxing them isn't worth it
+        .type_attribute(
+            "lakh.ServerMessage.message",
+            "#[allow(clippy::large_enum_variant)]",
+        )
+        .compile(
+            &["src/proto/lakh.proto", "src/proto/wal.proto"],
+            &["src/proto"],
+        )
+        .unwrap();
 }
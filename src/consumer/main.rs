@@ -1,5 +1,7 @@
 use std::collections::HashMap;
+use std::time::Duration;
 use tokio::sync::mpsc;
+use tokio::time::delay_for;
 use tonic::metadata::MetadataValue;
 use tonic::Request;
 
@@ -8,9 +10,12 @@ pub mod pb {
 }
 
 use pb::lakh_client::LakhClient;
-use pb::{JobResult, JobStatus};
+use pb::server_message;
+use pb::worker_message::Message;
+use pb::{JobResult, JobStatus, WorkerMessage};
 
-type JobHandler = fn(Vec<String>);
+/// Returns output of the job, encoding is up to producers and workers.
+type JobHandler = fn(Vec<String>) -> Vec<u8>;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -25,19 +30,49 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let mut req = Request::new(rx);
     req.metadata_mut()
         .insert("job_names", MetadataValue::from_static("add;sub"));
+    // jobs are handled one by one so there is no point in receiving more at once
+    req.metadata_mut()
+        .insert("max_in_flight", MetadataValue::from_static("1"));
+
+    // let the server know we're alive even when there are no jobs to report on
+    let mut heartbeat_tx = tx.clone();
+    tokio::spawn(async move {
+        loop {
+            delay_for(Duration::from_secs(5)).await;
+            let heartbeat = WorkerMessage {
+                message: Some(Message::Heartbeat(())),
+            };
+            if heartbeat_tx.send(heartbeat).await.is_err() {
+                break;
+            }
+        }
+    });
 
     let res = client.join(req).await?;
     let mut inbound = res.into_inner();
 
-    while let Some(job) = inbound.message().await? {
+    while let Some(msg) = inbound.message().await? {
+        let job = match msg.message {
+            Some(server_message::Message::Job(job)) => job,
+            // jobs are handled synchronously so there is nothing to stop
+            Some(server_message::Message::Cancelled(_)) | None => continue,
+        };
         let handler = jobs.get(job.name.as_str()).unwrap();
-        handler(job.args.clone());
+        let payload = handler(job.args.clone());
         // realistically job handlers should return `Result`
         // and returned status should be based on that
-        tx.send(JobResult {
+        let result = JobResult {
             job_id: job.id,
             job_name: job.name,
             status: JobStatus::Succeeded.into(),
+            // failed jobs should describe what went wrong
+            error_message: String::new(),
+            error_class: String::new(),
+            backtrace: String::new(),
+            payload,
+        };
+        tx.send(WorkerMessage {
+            message: Some(Message::Result(result)),
         })
         .await?
     }
@@ -45,16 +80,18 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     Ok(())
 }
 
-fn add(args: Vec<String>) {
+fn add(args: Vec<String>) -> Vec<u8> {
     let a = args[0].parse::<i32>().unwrap();
     let b = args[1].parse::<i32>().unwrap();
     let res = a + b;
     println!("add result: {}", res);
+    res.to_string().into_bytes()
 }
 
-fn sub(args: Vec<String>) {
+fn sub(args: Vec<String>) -> Vec<u8> {
     let a = args[0].parse::<i32>().unwrap();
     let b = args[1].parse::<i32>().unwrap();
     let res = a - b;
     println!("sub result: {}", res);
+    res.to_string().into_bytes()
 }
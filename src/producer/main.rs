@@ -9,7 +9,10 @@ pub mod pb {
 
 use pb::job::ExecutionTime;
 use pb::lakh_client::LakhClient;
-use pb::Job;
+use pb::{
+    Backoff, EnqueueStatus, Job, JobResultQuery, JobStatus, MisfireAction, MisfirePolicy,
+    RetryPolicy,
+};
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -22,6 +25,26 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             seconds: 10,
             nanos: 0,
         }),
+        priority: 10,
+        unique_key: "add-1-1".into(),
+        unique_for: None,
+        affinity_key: String::new(),
+        // retry every 2s, 4s, 8s... but wait at most a minute
+        retry: Some(RetryPolicy {
+            max_attempts: 10,
+            backoff: Backoff::Exponential.into(),
+            base_delay: Some(prost_types::Duration {
+                seconds: 2,
+                nanos: 0,
+            }),
+            max_delay: Some(prost_types::Duration {
+                seconds: 60,
+                nanos: 0,
+            }),
+            jitter: Some(0.1),
+        }),
+        misfire: None,
+        checkpoint: Vec::new(),
     };
     let job2 = Job {
         id: nanoid!(),
@@ -32,6 +55,13 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             nanos: 0,
         })),
         reservation_time: None,
+        priority: 0,
+        unique_key: String::new(),
+        unique_for: None,
+        affinity_key: String::new(),
+        retry: None,
+        misfire: None,
+        checkpoint: Vec::new(),
     };
 
     // create timestamp 10s into the future
@@ -53,16 +83,62 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             seconds: 20,
             nanos: 0,
         }),
+        priority: 0,
+        unique_key: String::new(),
+        unique_for: None,
+        affinity_key: String::new(),
+        retry: None,
+        // no point in running it if it's late by more than 5s
+        misfire: Some(MisfirePolicy {
+            action: MisfireAction::Skip.into(),
+            tolerance: Some(prost_types::Duration {
+                seconds: 5,
+                nanos: 0,
+            }),
+        }),
+        checkpoint: Vec::new(),
     };
 
+    let job1_id = job1.id.clone();
     let mut client = LakhClient::connect("http://[::1]:50051").await?;
     let mut req = Request::new(stream::iter(vec![job1, job2, job3]));
     req.metadata_mut()
         .insert("job_names", MetadataValue::from_static("add;sub"));
 
-    match client.work(req).await {
-        Ok(_) => {}
-        Err(e) => println!("something went wrong: {:?}", e),
+    let mut acks = match client.work(req).await {
+        Ok(res) => res.into_inner(),
+        Err(e) => {
+            println!("something went wrong: {:?}", e);
+            return Ok(());
+        }
+    };
+
+    // every job gets acknowledged once server decides what to do with it
+    while let Some(ack) = acks.message().await? {
+        match EnqueueStatus::from_i32(ack.status) {
+            Some(EnqueueStatus::Accepted) => println!("job {} accepted", ack.job_id),
+            _ => println!("job {} not accepted: {}", ack.job_id, ack.reason),
+        }
+    }
+
+    // outcome of a job can be awaited, `GetJobResult` asks without waiting
+    let query = JobResultQuery {
+        id: job1_id,
+        timeout: Some(Duration::from_secs(30).into()),
+    };
+    match client.await_job_result(query).await {
+        Ok(res) => {
+            let res = res.into_inner();
+            match JobStatus::from_i32(res.status) {
+                Some(JobStatus::Succeeded) => println!(
+                    "job {} returned {}",
+                    res.job_id,
+                    String::from_utf8_lossy(&res.payload)
+                ),
+                _ => println!("job {} failed: {}", res.job_id, res.error_message),
+            }
+        }
+        Err(e) => println!("no result: {}", e.message()),
     }
 
     Ok(())
@@ -0,0 +1,323 @@
+use rand::Rng;
+use serde::Deserialize;
+use std::convert::TryFrom;
+use std::time::Duration;
+
+use crate::pb::{self, RetryPolicy};
+
+/// Upper bound of a single delay unless configured otherwise,
+/// faktory-style backoff reaches it around the 25th attempt.
+pub const DEFAULT_MAX_DELAY: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+const DEFAULT_BASE_DELAY: Duration = Duration::from_secs(1);
+
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum BackoffKind {
+    /// `15 + count^4 + rand(0..30) * (count + 1)` seconds,
+    /// see https://github.com/contribsys/faktory/wiki/Job-Errors
+    #[default]
+    Faktory,
+    /// `base * 2^(count - 1)`
+    Exponential,
+    /// `base`
+    Fixed,
+    /// `base * count`
+    Linear,
+}
+
+impl BackoffKind {
+    /// `None` if job leaves the choice to the server.
+    fn from_pb(kind: pb::Backoff) -> Option<Self> {
+        match kind {
+            pb::Backoff::Unspecified => None,
+            pb::Backoff::Faktory => Some(Self::Faktory),
+            pb::Backoff::Exponential => Some(Self::Exponential),
+            pb::Backoff::Fixed => Some(Self::Fixed),
+            pb::Backoff::Linear => Some(Self::Linear),
+        }
+    }
+}
+
+/// Decides how long to wait before the next attempt of a failed job.
+///
+/// `count` in the formulas is the number of attempts made so far. Computed delay
+/// is shifted by a random fraction of itself no greater than `jitter` and then
+/// capped at `max_delay`.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(from = "BackoffConfig")]
+pub struct Backoff {
+    pub kind: BackoffKind,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    pub jitter: f64,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            kind: BackoffKind::default(),
+            base_delay: DEFAULT_BASE_DELAY,
+            max_delay: DEFAULT_MAX_DELAY,
+            jitter: 0.0,
+        }
+    }
+}
+
+impl Backoff {
+    /// Backoff described by job's own retry policy, unset fields are taken from `fallback`.
+    pub fn from_policy(policy: &RetryPolicy, fallback: &Self) -> Self {
+        let duration =
+            |d: &Option<prost_types::Duration>| d.clone().and_then(|d| Duration::try_from(d).ok());
+        Self {
+            kind: BackoffKind::from_pb(policy.backoff()).unwrap_or(fallback.kind),
+            base_delay: duration(&policy.base_delay).unwrap_or(fallback.base_delay),
+            max_delay: duration(&policy.max_delay).unwrap_or(fallback.max_delay),
+            jitter: policy.jitter.map_or(fallback.jitter, f64::from),
+        }
+    }
+
+    pub fn delay(&self, count: u32, rng: &mut impl Rng) -> Duration {
+        let delay = match self.kind {
+            BackoffKind::Faktory => {
+                let count = count as u64;
+                let r = rng.gen_range(0, 30);
+                let seconds = 15u64
+                    .saturating_add(count.saturating_pow(4))
+                    .saturating_add(r * count.saturating_add(1));
+                Duration::from_secs(seconds)
+            }
+            BackoffKind::Exponential => {
+                let factor = 2u32.saturating_pow(count.saturating_sub(1));
+                self.base_delay.saturating_mul(factor)
+            }
+            BackoffKind::Fixed => self.base_delay,
+            BackoffKind::Linear => self.base_delay.saturating_mul(count),
+        };
+
+        // NaN fails the comparison as well
+        let jitter = self.jitter.clamp(0.0, 1.0);
+        let delay = if jitter > 0.0 {
+            let shift = rng.gen_range(-jitter, jitter);
+            Duration::try_from_secs_f64(delay.as_secs_f64() * (1.0 + shift)).unwrap_or(delay)
+        } else {
+            delay
+        };
+        delay.min(self.max_delay)
+    }
+}
+
+/// `Backoff` as written in `config.toml`, durations are in seconds.
+#[derive(Deserialize)]
+struct BackoffConfig {
+    #[serde(default)]
+    kind: BackoffKind,
+    base_delay: Option<f64>,
+    max_delay: Option<f64>,
+    #[serde(default)]
+    jitter: f64,
+}
+
+impl From<BackoffConfig> for Backoff {
+    fn from(config: BackoffConfig) -> Self {
+        let seconds = |s: Option<f64>| s.and_then(|s| Duration::try_from_secs_f64(s).ok());
+        Self {
+            kind: config.kind,
+            base_delay: seconds(config.base_delay).unwrap_or(DEFAULT_BASE_DELAY),
+            max_delay: seconds(config.max_delay).unwrap_or(DEFAULT_MAX_DELAY),
+            jitter: config.jitter,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::mock::StepRng;
+
+    /// Always picks the lowest value of a range.
+    fn lowest() -> StepRng {
+        StepRng::new(0, 0)
+    }
+
+    fn secs(s: u64) -> Duration {
+        Duration::from_secs(s)
+    }
+
+    fn schedule(backoff: &Backoff, counts: std::ops::RangeInclusive<u32>) -> Vec<Duration> {
+        counts.map(|c| backoff.delay(c, &mut lowest())).collect()
+    }
+
+    #[test]
+    fn faktory_schedule() {
+        let backoff = Backoff::default();
+        assert_eq!(
+            schedule(&backoff, 1..=5),
+            vec![secs(16), secs(31), secs(96), secs(271), secs(640)]
+        );
+    }
+
+    #[test]
+    fn faktory_random_part_grows_with_count() {
+        let backoff = Backoff::default();
+        let mut rng = rand::thread_rng();
+        for count in 1..=20u64 {
+            for _ in 0..100 {
+                let delay = backoff.delay(count as u32, &mut rng);
+                let min = 15 + count.pow(4);
+                assert!(delay >= secs(min));
+                assert!(delay <= secs(min + 29 * (count + 1)));
+            }
+        }
+    }
+
+    #[test]
+    fn faktory_does_not_overflow() {
+        let backoff = Backoff::default();
+        assert_eq!(backoff.delay(255, &mut lowest()), DEFAULT_MAX_DELAY);
+        assert_eq!(backoff.delay(u32::MAX, &mut lowest()), DEFAULT_MAX_DELAY);
+    }
+
+    #[test]
+    fn exponential_schedule() {
+        let backoff = Backoff {
+            kind: BackoffKind::Exponential,
+            base_delay: secs(2),
+            max_delay: secs(60),
+            jitter: 0.0,
+        };
+        assert_eq!(
+            schedule(&backoff, 1..=7),
+            vec![
+                secs(2),
+                secs(4),
+                secs(8),
+                secs(16),
+                secs(32),
+                secs(60),
+                secs(60)
+            ]
+        );
+        assert_eq!(backoff.delay(u32::MAX, &mut lowest()), secs(60));
+    }
+
+    #[test]
+    fn fixed_schedule() {
+        let backoff = Backoff {
+            kind: BackoffKind::Fixed,
+            base_delay: Duration::from_millis(500),
+            ..Backoff::default()
+        };
+        assert_eq!(
+            schedule(&backoff, 1..=3),
+            vec![Duration::from_millis(500); 3]
+        );
+    }
+
+    #[test]
+    fn linear_schedule() {
+        let backoff = Backoff {
+            kind: BackoffKind::Linear,
+            base_delay: secs(10),
+            max_delay: secs(35),
+            jitter: 0.0,
+        };
+        assert_eq!(
+            schedule(&backoff, 1..=5),
+            vec![secs(10), secs(20), secs(30), secs(35), secs(35)]
+        );
+    }
+
+    #[test]
+    fn jitter_stays_within_bounds() {
+        let backoff = Backoff {
+            kind: BackoffKind::Fixed,
+            base_delay: secs(10),
+            jitter: 0.2,
+            ..Backoff::default()
+        };
+        assert_eq!(backoff.delay(1, &mut lowest()), secs(8));
+
+        let mut rng = rand::thread_rng();
+        for _ in 0..1000 {
+            let delay = backoff.delay(1, &mut rng);
+            assert!(delay >= secs(8) && delay <= secs(12));
+        }
+    }
+
+    #[test]
+    fn jitter_cannot_exceed_cap() {
+        let backoff = Backoff {
+            kind: BackoffKind::Fixed,
+            base_delay: secs(10),
+            max_delay: secs(10),
+            jitter: 1.0,
+        };
+        let mut rng = rand::thread_rng();
+        for _ in 0..1000 {
+            assert!(backoff.delay(1, &mut rng) <= secs(10));
+        }
+    }
+
+    #[test]
+    fn policy_overrides_fallback() {
+        let fallback = Backoff {
+            kind: BackoffKind::Linear,
+            base_delay: secs(3),
+            max_delay: secs(30),
+            jitter: 0.5,
+        };
+        let policy = RetryPolicy {
+            backoff: pb::Backoff::Exponential.into(),
+            max_delay: Some(secs(100).into()),
+            jitter: Some(0.0),
+            ..RetryPolicy::default()
+        };
+        let backoff = Backoff::from_policy(&policy, &fallback);
+        assert_eq!(
+            backoff,
+            Backoff {
+                kind: BackoffKind::Exponential,
+                base_delay: secs(3),
+                max_delay: secs(100),
+                jitter: 0.0,
+            }
+        );
+    }
+
+    #[test]
+    fn unset_policy_falls_back() {
+        let fallback = Backoff {
+            kind: BackoffKind::Linear,
+            base_delay: secs(3),
+            max_delay: secs(30),
+            jitter: 0.5,
+        };
+        let policy = RetryPolicy {
+            max_attempts: 5,
+            ..RetryPolicy::default()
+        };
+        assert_eq!(Backoff::from_policy(&policy, &fallback), fallback);
+    }
+
+    #[test]
+    fn parses_config() {
+        let backoff: Backoff = toml::from_str(
+            r#"
+            kind = "exponential"
+            base_delay = 0.25
+            jitter = 0.1
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            backoff,
+            Backoff {
+                kind: BackoffKind::Exponential,
+                base_delay: Duration::from_millis(250),
+                max_delay: DEFAULT_MAX_DELAY,
+                jitter: 0.1,
+            }
+        );
+    }
+}
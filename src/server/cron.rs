@@ -0,0 +1,85 @@
+use chrono::Utc;
+use chrono_tz::Tz;
+use cron::Schedule;
+use nanoid::nanoid;
+use std::str::FromStr;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::delay_for;
+use tracing::{info, instrument, warn};
+use tracing_futures::Instrument;
+
+use crate::executor::ExecutorCtl;
+use crate::pb::job::ExecutionTime;
+use crate::pb::{CronJob, EnqueueStatus};
+
+/// Dropping the handle stops the cron job.
+#[derive(Debug)]
+pub struct CronHandle(mpsc::Sender<()>);
+
+impl CronHandle {
+    pub async fn stop(mut self) {
+        let _ = self.0.send(()).await;
+    }
+}
+
+#[derive(Debug)]
+pub struct Cron {
+    schedule: Schedule,
+    timezone: Tz,
+}
+
+impl Cron {
+    pub fn parse(cron_job: &CronJob) -> Result<Self, String> {
+        let schedule = Schedule::from_str(&cron_job.schedule)
+            .map_err(|e| format!("invalid cron expression: {}", e))?;
+        let timezone = match cron_job.timezone.as_str() {
+            "" => Tz::UTC,
+            tz => tz.parse()?,
+        };
+        Ok(Self { schedule, timezone })
+    }
+
+    #[instrument(name = "cron", skip(self, to_exec))]
+    pub fn spawn(self, cron_job: CronJob, mut to_exec: mpsc::Sender<ExecutorCtl>) -> CronHandle {
+        let (tx, mut rx) = mpsc::channel(1);
+
+        info!(message = "created", cron_id = %cron_job.id);
+        let cron = async move {
+            let template = cron_job.template.unwrap_or_default();
+
+            while let Some(next) = self.schedule.upcoming(self.timezone).next() {
+                let wait_dur = (next.with_timezone(&Utc) - Utc::now())
+                    .to_std()
+                    .unwrap_or_default();
+                tokio::select! {
+                    _ = delay_for(wait_dur) => {},
+                    _ = rx.recv() => break,
+                }
+
+                let mut job = template.clone();
+                job.id = nanoid!();
+                job.execution_time = Some(ExecutionTime::Immediate(()));
+                info!(message = "tick", job_name = %job.name, job_id = %job.id);
+                let (ack_tx, ack_rx) = oneshot::channel();
+                if to_exec
+                    .send(ExecutorCtl::WorkOn(job, ack_tx))
+                    .await
+                    .is_err()
+                {
+                    warn!(message = "executor is gone", job_name = %template.name);
+                    break;
+                }
+                if let Ok(ack) = ack_rx.await {
+                    if ack.status != EnqueueStatus::Accepted as i32 {
+                        warn!(message = "tick not enqueued", job_id = %ack.job_id, reason = %ack.reason);
+                    }
+                }
+            }
+
+            info!(message = "stopped", cron_id = %cron_job.id);
+        };
+        tokio::spawn(cron.in_current_span());
+
+        CronHandle(tx)
+    }
+}
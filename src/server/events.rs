@@ -0,0 +1,46 @@
+use std::time::SystemTime;
+use tokio::sync::broadcast;
+
+use crate::pb::{Event, EventKind, Job};
+
+/// How many events a subscriber may fall behind before it starts missing them.
+const CAPACITY: usize = 1024;
+
+/// Fans lifecycle events of jobs and workers out to `Subscribe` callers.
+#[derive(Debug, Clone)]
+pub struct Events(broadcast::Sender<Event>);
+
+impl Events {
+    pub fn new() -> Self {
+        let (tx, _) = broadcast::channel(CAPACITY);
+        Self(tx)
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.0.subscribe()
+    }
+
+    /// Stamps the event with current time, it's dropped if nobody listens.
+    pub fn publish(&self, mut event: Event) {
+        event.at = Some(SystemTime::now().into());
+        let _ = self.0.send(event);
+    }
+}
+
+impl Event {
+    pub fn new(kind: EventKind, job_name: &str) -> Self {
+        let mut event = Self {
+            job_name: job_name.to_owned(),
+            ..Self::default()
+        };
+        event.set_kind(kind);
+        event
+    }
+
+    pub fn job(kind: EventKind, job: &Job) -> Self {
+        Self {
+            job_id: job.id.clone(),
+            ..Self::new(kind, &job.name)
+        }
+    }
+}
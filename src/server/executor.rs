@@ -1,24 +1,59 @@
-use rand::seq::IteratorRandom;
-use std::collections::HashMap;
-use std::time::Duration;
-use tokio::sync::{broadcast, mpsc};
-use tokio::time::delay_for;
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::convert::TryFrom;
+use std::io;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::error::SendError;
+use tokio::sync::{mpsc, oneshot, Mutex};
+use tokio::time::{delay_for, interval};
 use tracing::{info, instrument, warn};
 use tracing_futures::Instrument;
 
-use crate::pb::{Job, JobResult, JobStatus};
-use crate::task::{FailReason, Task, TaskCtl};
+use crate::events::Events;
+use crate::metrics::Metrics;
+use crate::pb::{
+    DeadJob, EnqueueAck, EnqueueStatus, Event, EventKind, Failure, FailureReason, Job, JobResult,
+    JobStatus, Progress,
+};
+use crate::storage::Storage;
+use crate::strategy::Strategy;
+use crate::task::{Report, Task, TaskCtl, TaskHandle};
 use crate::worker::{Worker, WorkerId};
+use crate::{Config, JobConfig, RequeuePolicy};
 
 #[derive(Debug)]
 pub enum ExecutorCtl {
-    WorkOn(Job),
+    /// Ack is sent back on a one-shot so executor never waits for the producer.
+    WorkOn(Job, oneshot::Sender<EnqueueAck>),
+    /// Job of `WorkOn` was written to storage (or not), it's time to ack it.
+    Enqueued(Job, io::Result<()>, oneshot::Sender<EnqueueAck>),
+    Restore(Job, u8),
     AddWorker(Worker),
     RemoveWorker(WorkerId),
-    ProvideWorker(mpsc::Sender<Worker>),
+    /// Id, priority and affinity key of the job along with where to send the worker.
+    ProvideWorker(String, i32, String, mpsc::Sender<Worker>),
+    FeedStarving,
+    /// Job id and worker now holding its reservation.
+    ReserveWorker(String, WorkerId),
+    ReleaseWorker(String),
     HandleJobResult(JobResult),
-    HandleDyingJob(Job, FailReason),
-    ReportDeadJobs(mpsc::Sender<Vec<Job>>),
+    /// Worker asks to extend reservation of a job by given duration.
+    TouchJob(String, WorkerId, Option<Duration>),
+    HandleProgress(WorkerId, Progress),
+    ReportJob(String, mpsc::Sender<Report>),
+    /// Every task reports to given channel.
+    ReportJobs(mpsc::Sender<Report>),
+    WatchJob(String, mpsc::Sender<Progress>),
+    CancelJob(String, mpsc::Sender<()>),
+    /// Replies with `None` if the job hasn't finished yet.
+    GetResult(String, mpsc::Sender<Option<JobResult>>),
+    /// Replies once the job finishes.
+    AwaitResult(String, mpsc::Sender<JobResult>),
+    /// Drops results and unique locks that outlived their purpose.
+    ExpireResults,
+    HandleFinishedJob(Job, Option<JobResult>),
+    HandleDyingJob(DeadJob),
 }
 
 #[derive(Debug)]
@@ -38,87 +73,428 @@ impl std::ops::DerefMut for ExecutorHandle {
     }
 }
 
+/// Task waiting for a worker to become available.
+#[derive(Debug)]
+struct Starving {
+    job_id: String,
+    priority: i32,
+    seq: u64,
+    affinity_key: String,
+    tx: mpsc::Sender<Worker>,
+}
+
+impl Ord for Starving {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // higher priority first, then first come first served
+        self.priority
+            .cmp(&other.priority)
+            .then_with(|| other.seq.cmp(&self.seq))
+    }
+}
+
+impl PartialOrd for Starving {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Starving {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Starving {}
+
+/// Longest uniqueness window kept when job's own would overflow the clock.
+const MAX_UNIQUE_WINDOW: Duration = Duration::from_secs(10 * 365 * 24 * 60 * 60);
+
+/// Prevents jobs with the same `unique_key` from being enqueued while the
+/// holder is alive or its uniqueness window hasn't passed.
+#[derive(Debug)]
+struct UniqueLock {
+    job_id: String,
+    until: Instant,
+}
+
+/// Outcome of a finished job kept around for clients asking for it.
+#[derive(Debug)]
+struct StoredResult {
+    result: JobResult,
+    until: Instant,
+}
+
+impl UniqueLock {
+    fn new(job: &Job) -> Self {
+        let window = job
+            .unique_for
+            .clone()
+            .and_then(|d| Duration::try_from(d).ok())
+            .unwrap_or_default();
+        // overlong windows are rejected on enqueue, restored jobs may predate that
+        let now = Instant::now();
+        Self {
+            job_id: job.id.clone(),
+            until: now
+                .checked_add(window)
+                .unwrap_or_else(|| now + MAX_UNIQUE_WINDOW),
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct Executor {
     max_retry: u8,
+    failure_history: usize,
+    result_ttl: Duration,
+    requeue_policy: RequeuePolicy,
+    job_configs: HashMap<String, JobConfig>,
+    storage: Arc<dyn Storage>,
+    /// Ids of jobs pending under any name, storage keys jobs by id alone.
+    pending_ids: Arc<Mutex<HashSet<String>>>,
+    events: Events,
+    metrics: Metrics,
 }
 
 impl Executor {
-    pub fn new(max_retry: u8) -> Self {
-        Self { max_retry }
+    pub fn new(
+        config: &Config,
+        storage: Arc<dyn Storage>,
+        events: Events,
+        metrics: Metrics,
+    ) -> Self {
+        Self {
+            max_retry: config.max_retry,
+            failure_history: config.failure_history,
+            result_ttl: Duration::from_secs(config.result_ttl),
+            requeue_policy: config.requeue_policy,
+            job_configs: config.jobs.clone(),
+            storage,
+            pending_ids: Arc::new(Mutex::new(HashSet::new())),
+            events,
+            metrics,
+        }
     }
 
     #[instrument(name = "executor")]
     pub fn spawn(&self, job_name: String) -> ExecutorHandle {
         let (tx, mut rx) = mpsc::channel(100);
-        let task = Task::new(tx.clone(), self.max_retry);
+        let job_config = self.job_configs.get(&job_name).cloned().unwrap_or_default();
+        let task = Task::new(
+            tx.clone(),
+            self.max_retry,
+            self.failure_history,
+            job_config.backoff,
+            self.storage.clone(),
+            self.events.clone(),
+            self.metrics.clone(),
+        );
+        let storage = self.storage.clone();
+        let exec_tx = tx.clone();
+        let pending_ids = self.pending_ids.clone();
+        let requeue_policy = self.requeue_policy;
+        let result_ttl = self.result_ttl;
+        let events = self.events.clone();
+        let enqueued = self.metrics.enqueued.with_label_values(&[&job_name]);
+        let died = self.metrics.died.with_label_values(&[&job_name]);
+        let queue_depth = self.metrics.queue_depth.with_label_values(&[&job_name]);
+        let starving_tasks = self.metrics.starving.with_label_values(&[&job_name]);
 
-        info!(message = "created", %job_name);
+        info!(message = "created", %job_name, strategy = ?job_config.strategy);
         let exec = async move {
             let mut workers = HashMap::new();
-            let mut tasks = HashMap::new();
-            let mut dead_jobs = Vec::new();
-            let (starved_tasks_tx, _) = broadcast::channel(10);
+            let mut strategy = job_config.strategy.build();
+            let mut tasks: HashMap<String, TaskHandle> = HashMap::new();
+            let mut reservations: HashMap<String, WorkerId> = HashMap::new();
+            let mut unique_locks: HashMap<String, UniqueLock> = HashMap::new();
+            // jobs being written to storage, they're about to become tasks
+            let mut enqueuing: HashSet<String> = HashSet::new();
+            let mut results: HashMap<String, StoredResult> = HashMap::new();
+            let mut awaiting: HashMap<String, Vec<mpsc::Sender<JobResult>>> = HashMap::new();
+            let mut starving = BinaryHeap::new();
+            let mut starving_seq: u64 = 0;
+            // workers are shared between executors so slots freed by other executors'
+            // tasks are only noticed periodically
+            let mut feed_interval = interval(Duration::from_secs(1));
+            let mut expire_interval = interval(Duration::from_secs(10));
+
+            loop {
+                // reflects whatever previous message changed
+                queue_depth.set(tasks.len() as i64);
+                starving_tasks.set(starving.len() as i64);
+
+                let ctl = tokio::select! {
+                    ctl = rx.recv() => match ctl {
+                        Some(ctl) => ctl,
+                        None => break,
+                    },
+                    _ = feed_interval.tick() => ExecutorCtl::FeedStarving,
+                    _ = expire_interval.tick() => ExecutorCtl::ExpireResults,
+                };
 
-            while let Some(ctl) = rx.recv().await {
                 match ctl {
-                    ExecutorCtl::WorkOn(j) => {
+                    ExecutorCtl::WorkOn(j, ack_tx) => {
+                        let mut pending = pending_ids.lock().await;
+                        if pending.contains(&j.id) {
+                            warn!(message = "duplicate job id", job_id = %j.id, %job_name);
+                            let ack = ack(&j.id, EnqueueStatus::Duplicate, "duplicate job id");
+                            let _ = ack_tx.send(ack);
+                            continue;
+                        }
+                        if !j.unique_key.is_empty() {
+                            if let Some(lock) = unique_locks.get(&j.unique_key) {
+                                let held = tasks.contains_key(&lock.job_id)
+                                    || enqueuing.contains(&lock.job_id);
+                                if held || lock.until > Instant::now() {
+                                    info!(
+                                        message = "duplicate job coalesced",
+                                        job_id = %j.id,
+                                        holder_id = %lock.job_id,
+                                        %job_name
+                                    );
+                                    let reason = format!("duplicate of job `{}`", lock.job_id);
+                                    let ack = ack(&j.id, EnqueueStatus::Duplicate, reason);
+                                    let _ = ack_tx.send(ack);
+                                    continue;
+                                }
+                            }
+                            unique_locks.insert(j.unique_key.clone(), UniqueLock::new(&j));
+                        }
+
+                        // producer is told about the job only once it's safe, writing
+                        // it down shouldn't hold up the rest of the executor though
+                        pending.insert(j.id.clone());
+                        enqueuing.insert(j.id.clone());
+                        let storage = storage.clone();
+                        let mut exec_tx = exec_tx.clone();
+                        tokio::spawn(async move {
+                            let res = storage.enqueue(&j).await;
+                            let _ = exec_tx.send(ExecutorCtl::Enqueued(j, res, ack_tx)).await;
+                        });
+                    }
+                    ExecutorCtl::Enqueued(j, res, ack_tx) => {
+                        enqueuing.remove(&j.id);
+                        if let Err(e) = res {
+                            pending_ids.lock().await.remove(&j.id);
+                            let lock = unique_locks.get(&j.unique_key);
+                            if lock.is_some_and(|lock| lock.job_id == j.id) {
+                                unique_locks.remove(&j.unique_key);
+                            }
+                            let reason = format!("failed to persist job: {}", e);
+                            let _ = ack_tx.send(ack(&j.id, EnqueueStatus::Rejected, reason));
+                            continue;
+                        }
+                        // job got enqueued again (e.g. retried dead job), old outcome is stale
+                        results.remove(&j.id);
+                        events.publish(Event::job(EventKind::Enqueued, &j));
+                        enqueued.inc();
+                        let _ = ack_tx.send(ack(&j.id, EnqueueStatus::Accepted, ""));
+                        let key = j.id.clone();
+                        let task = task.spawn(j, 0);
+                        tasks.insert(key, task);
+                    }
+                    ExecutorCtl::Restore(j, try_count) => {
+                        pending_ids.lock().await.insert(j.id.clone());
+                        if !j.unique_key.is_empty() {
+                            unique_locks.insert(j.unique_key.clone(), UniqueLock::new(&j));
+                        }
                         let key = j.id.clone();
-                        let task = task.spawn(j);
+                        let task = task.spawn(j, try_count);
                         tasks.insert(key, task);
                     }
                     ExecutorCtl::AddWorker(w) => {
                         workers.insert(w.id.clone(), w.clone());
                         info!(message = "worker added", id = %w.id, %job_name);
-                        // if this is our first worker we might have a bunch of
-                        // starved tasks so we broadcast it to everyone interested
-                        if workers.len() == 1 {
-                            let _ = starved_tasks_tx.send(w);
-                        }
+                        events.publish(Event {
+                            worker_id: w.id.clone(),
+                            ..Event::new(EventKind::WorkerJoined, &job_name)
+                        });
+                        feed_starving(&mut starving, &workers, strategy.as_mut());
                     }
                     ExecutorCtl::RemoveWorker(ref id) => {
                         workers.remove(id);
                         info!(message = "worker removed", %id, %job_name);
+                        events.publish(Event {
+                            worker_id: id.clone(),
+                            ..Event::new(EventKind::WorkerRemoved, &job_name)
+                        });
+
+                        if requeue_policy == RequeuePolicy::WaitForReservation {
+                            continue;
+                        }
+                        // don't wait for reservations of a worker that's gone to expire
+                        let lost = reservations
+                            .iter()
+                            .filter(|(_, worker_id)| *worker_id == id)
+                            .map(|(job_id, _)| job_id);
+                        for job_id in lost {
+                            if let Some(task) = tasks.get_mut(job_id) {
+                                let _ = task.send(TaskCtl::WorkerLost(id.clone())).await;
+                            }
+                        }
                     }
                     ExecutorCtl::HandleJobResult(res) => {
-                        match JobStatus::from_i32(res.status).unwrap() {
-                            JobStatus::Failed => {
+                        // job is left to its reservation time as if nothing came
+                        let status = match JobStatus::from_i32(res.status) {
+                            Some(status) => status,
+                            None => {
+                                warn!(
+                                    message = "unknown job status",
+                                    status = res.status,
+                                    job_id = %res.job_id,
+                                    %job_name
+                                );
+                                continue;
+                            }
+                        };
+                        match status {
+                            status @ (JobStatus::Failed | JobStatus::FailedPermanently) => {
                                 if let Some(task) = tasks.get_mut(&res.job_id) {
-                                    let _ = task.send(TaskCtl::Retry).await;
+                                    let failure = Failure {
+                                        message: res.error_message,
+                                        class: res.error_class,
+                                        backtrace: res.backtrace,
+                                        ..Failure::default()
+                                    };
+                                    let ctl = match status {
+                                        JobStatus::Failed => TaskCtl::Retry(failure),
+                                        _ => TaskCtl::GiveUp(failure),
+                                    };
+                                    let _ = task.send(ctl).await;
                                 }
                             }
                             JobStatus::Succeeded => {
-                                if let Some(mut task) = tasks.remove(&res.job_id) {
-                                    info!(message = "task removed", %res.job_id, %job_name);
-                                    let _ = task.send(TaskCtl::Terminate).await;
+                                if let Some(task) = tasks.get_mut(&res.job_id) {
+                                    let _ = task.send(TaskCtl::Terminate(res)).await;
                                 }
                             }
                         }
                     }
-                    ExecutorCtl::HandleDyingJob(j, _) => {
-                        dead_jobs.push(j);
+                    ExecutorCtl::TouchJob(job_id, worker_id, extend_by) => {
+                        if let Some(task) = tasks.get_mut(&job_id) {
+                            let _ = task.send(TaskCtl::Touch(worker_id, extend_by)).await;
+                        }
                     }
-                    ExecutorCtl::ProvideWorker(mut tx) => {
-                        let w = workers.values().choose(&mut rand::thread_rng());
-                        match w {
-                            Some(w) => tx.send(w.clone()).await.unwrap(),
-                            None => {
-                                let mut rx = starved_tasks_tx.subscribe();
-                                let starved_count = starved_tasks_tx.receiver_count();
-                                let feeder = async move {
-                                    let w = rx.recv().await.unwrap();
-                                    // don't feed all tasks at once to prevent "thundering herd"
-                                    let delay = 100 * (starved_count as u64 - 1);
-                                    delay_for(Duration::from_millis(delay)).await;
-                                    tx.send(w).await.unwrap();
-                                };
-                                tokio::spawn(feeder.instrument(tracing::info_span!("feeder")));
-                                warn!(%job_name, "starving {} tasks", starved_count);
+                    ExecutorCtl::HandleProgress(worker_id, progress) => {
+                        if let Some(task) = tasks.get_mut(&progress.job_id) {
+                            let _ = task.send(TaskCtl::Progress(worker_id, progress)).await;
+                        }
+                    }
+                    ExecutorCtl::WatchJob(job_id, tx) => {
+                        if let Some(task) = tasks.get_mut(&job_id) {
+                            let _ = task.send(TaskCtl::Watch(tx)).await;
+                        }
+                    }
+                    ExecutorCtl::ReportJob(job_id, mut tx) => {
+                        // unknown job just drops the sender
+                        // busy task is answered for rather than stalling the whole executor
+                        if let Some(task) = tasks.get_mut(&job_id) {
+                            if task.try_send(TaskCtl::Report(tx.clone())).is_err() {
+                                warn!(message = "task too busy to report", %job_id, %job_name);
+                                let _ = tx.send(Report::Busy).await;
                             }
                         }
                     }
-                    ExecutorCtl::ReportDeadJobs(mut tx) => {
-                        tx.send(dead_jobs.clone()).await.unwrap();
+                    ExecutorCtl::ReportJobs(mut tx) => {
+                        for (job_id, task) in tasks.iter_mut() {
+                            if task.try_send(TaskCtl::Report(tx.clone())).is_err() {
+                                warn!(message = "task too busy to report", %job_id, %job_name);
+                                let _ = tx.send(Report::Busy).await;
+                            }
+                        }
+                    }
+                    ExecutorCtl::CancelJob(job_id, mut tx) => {
+                        // task cleans up after itself, it just can't be reached anymore
+                        if let Some(mut task) = tasks.remove(&job_id) {
+                            let _ = task.send(TaskCtl::Cancel).await;
+                            // task might have been waiting for a worker
+                            starving.retain(|s| s.job_id != job_id);
+                            info!(message = "task cancelled", %job_id, %job_name);
+                            events.publish(Event {
+                                job_id,
+                                ..Event::new(EventKind::Cancelled, &job_name)
+                            });
+                            let _ = tx.send(()).await;
+                        }
+                    }
+                    ExecutorCtl::GetResult(job_id, mut tx) => {
+                        if let Some(stored) = results.get(&job_id) {
+                            if stored.until > Instant::now() {
+                                let _ = tx.send(Some(stored.result.clone())).await;
+                            }
+                        } else if tasks.contains_key(&job_id) {
+                            let _ = tx.send(None).await;
+                        }
+                    }
+                    ExecutorCtl::AwaitResult(job_id, mut tx) => {
+                        if let Some(stored) = results.get(&job_id) {
+                            if stored.until > Instant::now() {
+                                let _ = tx.send(stored.result.clone()).await;
+                            }
+                        } else if tasks.contains_key(&job_id) {
+                            awaiting.entry(job_id).or_default().push(tx);
+                        }
+                    }
+                    ExecutorCtl::ExpireResults => {
+                        let now = Instant::now();
+                        results.retain(|_, stored| stored.until > now);
+                        unique_locks.retain(|_, lock| {
+                            tasks.contains_key(&lock.job_id)
+                                || enqueuing.contains(&lock.job_id)
+                                || lock.until > now
+                        });
+                    }
+                    ExecutorCtl::HandleFinishedJob(j, result) => {
+                        tasks.remove(&j.id);
+                        pending_ids.lock().await.remove(&j.id);
+                        info!(message = "task removed", job_id = %j.id, %job_name);
+                        release_unique_lock(&mut unique_locks, &j);
+                        // jobs that were cancelled have no outcome, their waiters are dropped
+                        let waiters = awaiting.remove(&j.id).unwrap_or_default();
+                        if let Some(result) = result {
+                            store_result(&mut results, waiters, result, result_ttl).await;
+                        }
+                    }
+                    ExecutorCtl::HandleDyingJob(dead) => {
+                        died.inc();
+                        if let Some(j) = &dead.job {
+                            tasks.remove(&j.id);
+                            pending_ids.lock().await.remove(&j.id);
+                            release_unique_lock(&mut unique_locks, j);
+                            let waiters = awaiting.remove(&j.id).unwrap_or_default();
+                            let result = dead_result(&dead);
+                            store_result(&mut results, waiters, result, result_ttl).await;
+                        }
+                    }
+                    ExecutorCtl::ProvideWorker(job_id, priority, affinity_key, tx) => {
+                        // always go through the queue so that tasks with higher
+                        // priority waiting for a free worker are served first
+                        starving.push(Starving {
+                            job_id,
+                            priority,
+                            seq: starving_seq,
+                            affinity_key,
+                            tx,
+                        });
+                        starving_seq += 1;
+                        feed_starving(&mut starving, &workers, strategy.as_mut());
+                        if !starving.is_empty() {
+                            warn!(%job_name, "starving {} tasks", starving.len());
+                            events.publish(Event {
+                                starving: starving.len() as u32,
+                                ..Event::new(EventKind::Starving, &job_name)
+                            });
+                        }
+                    }
+                    ExecutorCtl::FeedStarving => {
+                        feed_starving(&mut starving, &workers, strategy.as_mut());
+                    }
+                    ExecutorCtl::ReserveWorker(job_id, worker_id) => {
+                        reservations.insert(job_id, worker_id);
+                    }
+                    ExecutorCtl::ReleaseWorker(ref job_id) => {
+                        reservations.remove(job_id);
+                        feed_starving(&mut starving, &workers, strategy.as_mut());
                     }
                 }
             }
@@ -128,3 +504,86 @@ impl Executor {
         ExecutorHandle(tx)
     }
 }
+
+/// Hands workers with free slots to starving tasks in order of priority.
+fn feed_starving(
+    starving: &mut BinaryHeap<Starving>,
+    workers: &HashMap<WorkerId, Worker>,
+    strategy: &mut dyn Strategy,
+) {
+    let mut delay = 0;
+    while let Some(next) = starving.peek() {
+        let w = match strategy.select(workers, &next.affinity_key) {
+            // reservation can still fail if other executor took the last slot
+            Some(w) if w.try_reserve() => w.clone(),
+            _ => break,
+        };
+
+        let Starving { mut tx, .. } = starving.pop().unwrap();
+        let feeder = async move {
+            // don't feed all tasks at once to prevent "thundering herd"
+            delay_for(Duration::from_millis(delay)).await;
+            if let Err(SendError(w)) = tx.send(w).await {
+                w.release();
+            }
+        };
+        tokio::spawn(feeder.instrument(tracing::info_span!("feeder")));
+        delay += 100;
+    }
+}
+
+pub fn ack(job_id: &str, status: EnqueueStatus, reason: impl Into<String>) -> EnqueueAck {
+    EnqueueAck {
+        job_id: job_id.to_owned(),
+        status: status.into(),
+        reason: reason.into(),
+    }
+}
+
+async fn store_result(
+    results: &mut HashMap<String, StoredResult>,
+    waiters: Vec<mpsc::Sender<JobResult>>,
+    result: JobResult,
+    ttl: Duration,
+) {
+    for mut tx in waiters {
+        let _ = tx.send(result.clone()).await;
+    }
+    let stored = StoredResult {
+        result,
+        until: Instant::now() + ttl,
+    };
+    results.insert(stored.result.job_id.clone(), stored);
+}
+
+/// Outcome of a job that ran out of luck, described by its last failure.
+fn dead_result(dead: &DeadJob) -> JobResult {
+    let job = dead.job.as_ref().unwrap();
+    let failure = dead.failures.last().cloned().unwrap_or_default();
+    let mut result = JobResult {
+        job_id: job.id.clone(),
+        job_name: job.name.clone(),
+        error_message: dead.last_error.clone(),
+        error_class: failure.class,
+        backtrace: failure.backtrace,
+        ..JobResult::default()
+    };
+    result.set_status(match dead.reason() {
+        FailureReason::FailedPermanently => JobStatus::FailedPermanently,
+        _ => JobStatus::Failed,
+    });
+    result
+}
+
+fn release_unique_lock(unique_locks: &mut HashMap<String, UniqueLock>, job: &Job) {
+    if job.unique_key.is_empty() {
+        return;
+    }
+    // if uniqueness window is still open the lock is dropped by the next
+    // enqueue with the same key or by `ExpireResults` once it closes
+    if let Some(lock) = unique_locks.get(&job.unique_key) {
+        if lock.job_id == job.id && lock.until <= Instant::now() {
+            unique_locks.remove(&job.unique_key);
+        }
+    }
+}
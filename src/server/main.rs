@@ -1,24 +1,103 @@
 use serde::Deserialize;
+use std::collections::HashMap;
 use tokio::fs;
 use tonic::transport::Server;
 use tracing::info;
 
 pub mod pb {
     tonic::include_proto!("lakh");
+
+    pub mod wal {
+        tonic::include_proto!("lakh.wal");
+    }
 }
 use pb::lakh_server::LakhServer;
 
+mod backoff;
+mod cron;
+mod events;
 mod executor;
 mod manager;
+mod metrics;
+mod query;
+mod storage;
+mod strategy;
 mod task;
 mod worker;
 
+use backoff::Backoff;
 use manager::Manager;
+use metrics::Metrics;
+use strategy::StrategyKind;
 
 #[derive(Deserialize)]
 pub struct Config {
     addr: String,
+    /// Where Prometheus metrics are served at `/metrics`, not served if unset.
+    metrics_addr: Option<String>,
     max_retry: u8,
+    /// Number of most recent failures kept for every job.
+    #[serde(default = "default_failure_history")]
+    failure_history: usize,
+    /// Seconds outcome of a finished job is kept for.
+    #[serde(default = "default_result_ttl")]
+    result_ttl: u64,
+    #[serde(default)]
+    storage: StorageConfig,
+    /// Seconds after which worker that sent nothing is considered dead.
+    heartbeat_timeout: Option<u64>,
+    /// What to do with jobs reserved by a worker that's gone.
+    #[serde(default)]
+    requeue_policy: RequeuePolicy,
+    /// Settings of particular job names.
+    #[serde(default)]
+    jobs: HashMap<String, JobConfig>,
+    #[serde(default)]
+    dead_jobs: DeadJobsConfig,
+}
+
+fn default_failure_history() -> usize {
+    5
+}
+
+fn default_result_ttl() -> u64 {
+    60 * 60
+}
+
+/// Retention of dead jobs, they're kept forever by default.
+#[derive(Deserialize, Default, Clone, Copy, Debug)]
+pub struct DeadJobsConfig {
+    /// Maximum number of dead jobs kept, oldest are dropped first.
+    limit: Option<usize>,
+    /// Seconds after which dead job is dropped.
+    ttl: Option<u64>,
+}
+
+#[derive(Deserialize, Default, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RequeuePolicy {
+    /// Send them to other workers right away.
+    #[default]
+    Immediate,
+    /// Let them fail once their reservation time elapses.
+    WaitForReservation,
+}
+
+#[derive(Deserialize, Default, Clone, Debug)]
+pub struct JobConfig {
+    #[serde(default)]
+    strategy: StrategyKind,
+    /// Used for jobs that don't bring their own retry policy.
+    #[serde(default)]
+    backoff: Backoff,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum StorageConfig {
+    #[default]
+    Memory,
+    Disk { path: String },
 }
 
 type Error = Box<dyn std::error::Error>;
@@ -34,9 +113,19 @@ async fn main() -> Result<(), Error> {
     let conf: Config = toml::from_str(&toml_str)?;
     let addr = conf.addr.parse()?;
 
+    let metrics = Metrics::new();
+    if let Some(metrics_addr) = &conf.metrics_addr {
+        metrics::spawn_server(metrics_addr.parse()?, metrics.clone())?;
+    }
+
+    let storage = storage::open(&conf.storage).await?;
+    storage::spawn_reaper(storage.clone(), conf.dead_jobs);
+    let manager = Manager::new(conf, storage, metrics);
+    manager.recover().await;
+
     info!("listening on {}", addr);
     Server::builder()
-        .add_service(LakhServer::new(Manager::new(conf)))
+        .add_service(LakhServer::new(manager))
         .serve(addr)
         .await?;
 
@@ -1,38 +1,207 @@
-use futures::{Stream, StreamExt};
+// tonic hands out `Status` as is, boxing it in every helper isn't worth it
+#![allow(clippy::result_large_err)]
+
+use futures::{stream, Stream, StreamExt};
 use nanoid::nanoid;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryFrom;
+use std::io;
 use std::pin::Pin;
-use tokio::sync::{mpsc, Mutex};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::sync::broadcast::RecvError;
+use tokio::sync::{mpsc, oneshot, Mutex};
+use tokio::time::timeout;
 use tonic::metadata::MetadataMap;
 use tonic::{Request, Response, Status};
-use tracing::{instrument, warn};
+use tracing::{info, instrument, warn};
 use tracing_futures::Instrument;
 
-use crate::executor::{Executor, ExecutorCtl, ExecutorHandle};
+use crate::cron::{Cron, CronHandle};
+use crate::events::Events;
+use crate::executor::{ack, Executor, ExecutorCtl, ExecutorHandle};
+use crate::metrics::Metrics;
+use crate::pb::job::ExecutionTime;
 use crate::pb::lakh_server::Lakh;
-use crate::pb::{DeadJobs, Job, JobResult};
-use crate::worker::Worker;
+use crate::pb::worker_message::Message;
+use crate::pb::{
+    CronJob, CronJobId, CronJobs, DeadJob, DeadJobCount, DeadJobs, DeadJobsQuery, EnqueueAck,
+    EnqueueStatus, Event, EventFilter, Job, JobId, JobInfo, JobName, JobResult, JobResultQuery,
+    JobState, Jobs, JobsQuery, Progress, Queue, Queues, ServerMessage, WorkerInfo, WorkerMessage,
+    Workers,
+};
+use crate::query;
+use crate::storage::Storage;
+use crate::task::Report;
+use crate::worker::{Worker, WorkerId};
 use crate::Config;
 
+/// Worker connected to `Join` as reported by `ListWorkers`.
+#[derive(Debug)]
+struct ConnectedWorker {
+    worker: Worker,
+    /// Everything except `in_flight` which changes all the time.
+    info: WorkerInfo,
+}
+
+/// How long tasks are given to report their state before the caller gets what's there.
+const REPORT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Longest delay, reservation or window a job may ask for, anything
+/// longer is most likely a mistake and would overflow clocks.
+const MAX_DURATION: Duration = Duration::from_secs(10 * 365 * 24 * 60 * 60);
+
 #[derive(Debug)]
 pub struct Manager {
     exec_handles: Mutex<HashMap<String, ExecutorHandle>>,
     exec_spawner: Executor,
+    cron_handles: Mutex<HashMap<String, CronHandle>>,
+    /// Shared with stream handlers of workers so they can remove themselves.
+    workers: Arc<Mutex<HashMap<WorkerId, ConnectedWorker>>>,
+    storage: Arc<dyn Storage>,
+    events: Events,
+    metrics: Metrics,
+    heartbeat_timeout: Option<Duration>,
 }
 
 impl Manager {
-    pub fn new(config: Config) -> Self {
+    pub fn new(config: Config, storage: Arc<dyn Storage>, metrics: Metrics) -> Self {
+        let events = Events::new();
         Self {
             exec_handles: Mutex::new(HashMap::new()),
-            exec_spawner: Executor::new(config.max_retry),
+            exec_spawner: Executor::new(&config, storage.clone(), events.clone(), metrics.clone()),
+            cron_handles: Mutex::new(HashMap::new()),
+            workers: Arc::new(Mutex::new(HashMap::new())),
+            storage,
+            events,
+            metrics,
+            heartbeat_timeout: config.heartbeat_timeout.map(Duration::from_secs),
+        }
+    }
+
+    /// Respawns jobs and cron jobs that were pending when the server last stopped.
+    pub async fn recover(&self) {
+        let mut guarded_handles = self.exec_handles.lock().await;
+
+        for (job, try_count) in self.storage.recover().await {
+            guarded_handles
+                .entry(job.name.clone())
+                .or_insert_with(|| self.exec_spawner.spawn(job.name.clone()))
+                .send(ExecutorCtl::Restore(job, try_count))
+                .await
+                .unwrap();
+        }
+        drop(guarded_handles);
+
+        for cron_job in self.storage.cron_jobs().await {
+            let id = cron_job.id.clone();
+            if let Err(e) = self.start_cron_job(cron_job).await {
+                warn!(message = "failed to restore cron job", cron_id = %id, %e);
+            }
+        }
+    }
+
+    async fn start_cron_job(&self, cron_job: CronJob) -> Result<(), String> {
+        let cron = Cron::parse(&cron_job)?;
+        let job_name = match &cron_job.template {
+            Some(template) if !template.name.is_empty() => template.name.clone(),
+            _ => return Err("missing job template".to_owned()),
+        };
+
+        let exec = self
+            .exec_handles
+            .lock()
+            .await
+            .entry(job_name.clone())
+            .or_insert_with(|| self.exec_spawner.spawn(job_name))
+            .clone();
+        let id = cron_job.id.clone();
+        let handle = cron.spawn(cron_job, exec);
+        self.cron_handles.lock().await.insert(id, handle);
+        Ok(())
+    }
+
+    /// Reports of every job that isn't done yet, of given job name if any.
+    /// Fails if some of them couldn't tell what state they're in.
+    async fn pending_jobs(&self, job_name: Option<&str>) -> Result<Vec<JobInfo>, Status> {
+        let (tx, mut rx) = mpsc::channel(100);
+        for (name, exec) in self.exec_handles.lock().await.iter_mut() {
+            if job_name.is_none_or(|n| n == name) {
+                exec.send(ExecutorCtl::ReportJobs(tx.clone()))
+                    .await
+                    .unwrap();
+            }
+        }
+        drop(tx);
+
+        // a task stuck handing its job to a worker doesn't answer until it's done
+        let mut jobs = Vec::new();
+        let mut busy = 0;
+        let collect = async {
+            while let Some(report) = rx.recv().await {
+                match report {
+                    Report::Job(info) => jobs.push(*info),
+                    Report::Busy => busy += 1,
+                }
+            }
+        };
+        if timeout(REPORT_TIMEOUT, collect).await.is_err() {
+            warn!(
+                message = "some jobs didn't report in time",
+                reported = jobs.len()
+            );
+            return Err(Status::unavailable("some jobs didn't report in time"));
+        }
+        if busy > 0 {
+            return Err(Status::unavailable(format!(
+                "{} jobs too busy to report",
+                busy
+            )));
+        }
+        Ok(jobs)
+    }
+
+    /// Enqueues dead job again as if it was never attempted,
+    /// if it's not accepted (or that fails) it stays dead.
+    async fn resurrect(&self, dead: DeadJob) -> Result<EnqueueAck, Status> {
+        let res = self.enqueue_dead(&dead).await;
+        if !matches!(&res, Ok(ack) if ack.status() == EnqueueStatus::Accepted) {
+            self.storage.bury(dead).await.map_err(storage_error)?;
         }
+        res
+    }
+
+    async fn enqueue_dead(&self, dead: &DeadJob) -> Result<EnqueueAck, Status> {
+        let mut job = match &dead.job {
+            Some(job) => job.clone(),
+            None => return Err(Status::internal("dead job record without job")),
+        };
+        job.execution_time = Some(ExecutionTime::Immediate(()));
+
+        let mut exec = self
+            .exec_handles
+            .lock()
+            .await
+            .entry(job.name.clone())
+            .or_insert_with(|| self.exec_spawner.spawn(job.name.clone()))
+            .clone();
+        let (tx, rx) = oneshot::channel();
+        exec.send(ExecutorCtl::WorkOn(job, tx)).await.unwrap();
+        rx.await
+            .map_err(|_| Status::internal("executor dropped the job"))
     }
 }
 
 #[tonic::async_trait]
 impl Lakh for Manager {
+    type WorkStream =
+        Pin<Box<dyn Stream<Item = Result<EnqueueAck, Status>> + Send + Sync + 'static>>;
+
     #[instrument(name = "producer", err)]
-    async fn work(&self, request: Request<tonic::Streaming<Job>>) -> Result<Response<()>, Status> {
+    async fn work(
+        &self,
+        request: Request<tonic::Streaming<Job>>,
+    ) -> Result<Response<Self::WorkStream>, Status> {
         let job_names = parse_job_names(request.metadata())?;
         let mut executors = HashMap::with_capacity(job_names.len());
         let mut guarded_handles = self.exec_handles.lock().await;
@@ -46,32 +215,82 @@ impl Lakh for Manager {
         }
         drop(guarded_handles);
 
-        let mut job_stream = request.into_inner();
-        while let Some(job) = job_stream.next().await {
-            let job = job?;
-            match executors.get_mut(&job.name) {
-                Some(exec) => exec.send(ExecutorCtl::WorkOn(job)).await.unwrap(),
-                None => warn!(
-                    message = "unknown job requested",
-                    job_name = %(&job.name),
-                    job_id = %(&job.id)
-                ),
+        let (mut tx, rx) = mpsc::channel(100);
+        let job_handler = async move {
+            let mut job_stream = request.into_inner();
+            while let Some(job) = job_stream.next().await {
+                let mut job = match job {
+                    Ok(j) => j,
+                    Err(_) => break,
+                };
+                if job.id.is_empty() {
+                    job.id = nanoid!();
+                }
+                if let Err(status) = validate_job(&job) {
+                    warn!(message = "invalid job", job_id = %(&job.id), reason = %status.message());
+                    // rejecting just this job keeps the stream going for the rest
+                    let ack = ack(&job.id, EnqueueStatus::Rejected, status.message());
+                    if tx.send(Ok(ack)).await.is_err() {
+                        break;
+                    }
+                    continue;
+                }
+
+                let ack = match executors.get_mut(&job.name) {
+                    Some(exec) => {
+                        let (ack_tx, ack_rx) = oneshot::channel();
+                        exec.send(ExecutorCtl::WorkOn(job, ack_tx)).await.unwrap();
+                        match ack_rx.await {
+                            Ok(ack) => ack,
+                            Err(_) => break,
+                        }
+                    }
+                    None => {
+                        warn!(
+                            message = "unknown job requested",
+                            job_name = %(&job.name),
+                            job_id = %(&job.id)
+                        );
+                        ack(&job.id, EnqueueStatus::Rejected, "unknown job name")
+                    }
+                };
+                if tx.send(Ok(ack)).await.is_err() {
+                    break;
+                }
             }
-        }
+        };
+        tokio::spawn(job_handler.in_current_span());
 
-        Ok(Response::new(()))
+        Ok(Response::new(Box::pin(rx) as Self::WorkStream))
     }
 
-    type JoinStream = Pin<Box<dyn Stream<Item = Result<Job, Status>> + Send + Sync + 'static>>;
+    type JoinStream =
+        Pin<Box<dyn Stream<Item = Result<ServerMessage, Status>> + Send + Sync + 'static>>;
 
     #[instrument(name = "consumer", err)]
     async fn join(
         &self,
-        job_result: Request<tonic::Streaming<JobResult>>,
+        worker_msg: Request<tonic::Streaming<WorkerMessage>>,
     ) -> Result<Response<Self::JoinStream>, Status> {
-        let job_names = parse_job_names(job_result.metadata())?;
+        let job_names = parse_job_names(worker_msg.metadata())?;
+        let max_in_flight = parse_max_in_flight(worker_msg.metadata())?;
         let (tx, rx) = mpsc::channel(10);
-        let w = Worker::new(nanoid!(), tx);
+        let mut w = Worker::new(nanoid!(), tx, max_in_flight);
+        let connected = ConnectedWorker {
+            worker: w.clone(),
+            info: WorkerInfo {
+                id: w.id.clone(),
+                peer_addr: worker_msg
+                    .remote_addr()
+                    .map_or(String::new(), |a| a.to_string()),
+                job_names: job_names.clone(),
+                in_flight: 0,
+                max_in_flight: max_in_flight as u32,
+                connected_since: Some(SystemTime::now().into()),
+            },
+        };
+        self.workers.lock().await.insert(w.id.clone(), connected);
+        self.metrics.connected_workers.inc();
         let mut executors = HashMap::with_capacity(job_names.len());
         let mut guarded_handles = self.exec_handles.lock().await;
 
@@ -84,53 +303,475 @@ impl Lakh for Manager {
             executors.insert(job_name.to_owned(), exec);
         }
 
+        let heartbeat_timeout = self.heartbeat_timeout;
+        let workers = self.workers.clone();
+        let connected_workers = self.metrics.connected_workers.clone();
         let result_handler = async move {
-            let mut result_stream = job_result.into_inner();
-            while let Some(job_result) = result_stream.next().await {
-                let job_result = match job_result {
-                    Ok(j) => j,
-                    Err(_) => break,
+            let mut msg_stream = worker_msg.into_inner();
+            let mut missed_heartbeat = false;
+            loop {
+                let msg = match heartbeat_timeout {
+                    Some(dur) => match timeout(dur, msg_stream.next()).await {
+                        Ok(msg) => msg,
+                        Err(_) => {
+                            warn!(message = "worker missed heartbeat", id = %w.id);
+                            missed_heartbeat = true;
+                            break;
+                        }
+                    },
+                    None => msg_stream.next().await,
+                };
+                let msg = match msg {
+                    Some(Ok(m)) => m,
+                    _ => break,
                 };
 
-                match executors.get_mut(&job_result.job_name) {
-                    Some(exec) => exec
-                        .send(ExecutorCtl::HandleJobResult(job_result))
-                        .await
-                        .unwrap(),
+                let (job_name, job_id, ctl) = match msg.message {
+                    Some(Message::Result(res)) => (
+                        res.job_name.clone(),
+                        res.job_id.clone(),
+                        ExecutorCtl::HandleJobResult(res),
+                    ),
+                    Some(Message::Touch(touch)) => {
+                        let extend_by = match touch.extend_by {
+                            Some(d) if d.seconds < 0 || d.nanos < 0 => {
+                                warn!(message = "negative extension", job_id = %touch.job_id);
+                                continue;
+                            }
+                            Some(d) if d.seconds as u64 >= MAX_DURATION.as_secs() => {
+                                warn!(message = "extension too long", job_id = %touch.job_id);
+                                continue;
+                            }
+                            d => d.and_then(|d| Duration::try_from(d).ok()),
+                        };
+                        let ctl =
+                            ExecutorCtl::TouchJob(touch.job_id.clone(), w.id.clone(), extend_by);
+                        (touch.job_name, touch.job_id, ctl)
+                    }
+                    Some(Message::Progress(progress)) => (
+                        progress.job_name.clone(),
+                        progress.job_id.clone(),
+                        ExecutorCtl::HandleProgress(w.id.clone(), progress),
+                    ),
+                    Some(Message::Heartbeat(_)) | None => continue,
+                };
+                match executors.get_mut(&job_name) {
+                    Some(exec) => exec.send(ctl).await.unwrap(),
                     None => warn!(
-                        message = "got unknown job result",
-                        job_name = %(&job_result.job_name),
-                        job_id = %(&job_result.job_id)
+                        message = "got message about unknown job",
+                        %job_name,
+                        %job_id
                     ),
                 }
             }
+
+            // worker is gone one way or another
+            workers.lock().await.remove(&w.id);
+            connected_workers.dec();
+            for exec in executors.values_mut() {
+                exec.send(ExecutorCtl::RemoveWorker(w.id.clone()))
+                    .await
+                    .unwrap();
+            }
+            // hung worker isn't reading its stream, so this must not wait for room in it
+            if missed_heartbeat {
+                w.disconnect(Status::deadline_exceeded("missed heartbeat"));
+            }
         };
         tokio::spawn(result_handler.in_current_span());
 
         Ok(Response::new(Box::pin(rx) as Self::JoinStream))
     }
 
-    async fn get_dead_jobs(&self, _req: Request<()>) -> Result<Response<DeadJobs>, Status> {
+    async fn get_dead_jobs(
+        &self,
+        req: Request<DeadJobsQuery>,
+    ) -> Result<Response<DeadJobs>, Status> {
+        let dead = self.storage.dead_jobs().await;
+        let res = query::dead_jobs(dead, req.get_ref())?;
+        Ok(Response::new(res))
+    }
+
+    async fn retry_dead_job(&self, req: Request<JobId>) -> Result<Response<EnqueueAck>, Status> {
+        let id = req.into_inner().id;
+        let mut dead = self
+            .storage
+            .remove_dead_jobs(&|d| is_job(d, &id))
+            .await
+            .map_err(storage_error)?;
+        match dead.pop() {
+            Some(d) => Ok(Response::new(self.resurrect(d).await?)),
+            None => Err(Status::not_found(format!("no dead job with id `{}`", id))),
+        }
+    }
+
+    async fn retry_dead_jobs(
+        &self,
+        req: Request<JobName>,
+    ) -> Result<Response<DeadJobCount>, Status> {
+        let name = req.into_inner().name;
+        let filter = |d: &DeadJob| matches!(&d.job, Some(j) if j.name == name);
+        let mut count = 0;
+        let dead = self
+            .storage
+            .remove_dead_jobs(&filter)
+            .await
+            .map_err(storage_error)?;
+        // every job gets its chance, the ones that fail stay dead
+        let mut failed = 0;
+        let mut last_err = None;
+        for d in dead {
+            match self.resurrect(d).await {
+                Ok(ack) if ack.status() == EnqueueStatus::Accepted => count += 1,
+                Ok(_) => {}
+                Err(e) => {
+                    failed += 1;
+                    last_err = Some(e);
+                }
+            }
+        }
+        if let Some(e) = last_err {
+            return Err(Status::new(
+                e.code(),
+                format!(
+                    "retried {} dead jobs, {} failed: {}",
+                    count,
+                    failed,
+                    e.message()
+                ),
+            ));
+        }
+        Ok(Response::new(DeadJobCount { count }))
+    }
+
+    async fn delete_dead_job(&self, req: Request<JobId>) -> Result<Response<()>, Status> {
+        let id = req.into_inner().id;
+        let dead = self
+            .storage
+            .remove_dead_jobs(&|d| is_job(d, &id))
+            .await
+            .map_err(storage_error)?;
+        if dead.is_empty() {
+            return Err(Status::not_found(format!("no dead job with id `{}`", id)));
+        }
+        Ok(Response::new(()))
+    }
+
+    async fn purge_dead_jobs(&self, _req: Request<()>) -> Result<Response<DeadJobCount>, Status> {
+        let dead = self
+            .storage
+            .remove_dead_jobs(&|_| true)
+            .await
+            .map_err(storage_error)?;
+        let count = dead.len() as u32;
+        info!(message = "purged dead jobs", count);
+        Ok(Response::new(DeadJobCount { count }))
+    }
+
+    async fn register_cron_job(
+        &self,
+        req: Request<CronJob>,
+    ) -> Result<Response<CronJobId>, Status> {
+        let mut cron_job = req.into_inner();
+        if cron_job.id.is_empty() {
+            cron_job.id = nanoid!();
+        }
+        if let Some(template) = &cron_job.template {
+            validate_job(template)?;
+        }
+        // replacing an existing cron job drops (and stops) its handle
+        self.start_cron_job(cron_job.clone())
+            .await
+            .map_err(Status::invalid_argument)?;
+        if let Err(e) = self.storage.register_cron_job(&cron_job).await {
+            if let Some(handle) = self.cron_handles.lock().await.remove(&cron_job.id) {
+                handle.stop().await;
+            }
+            return Err(storage_error(e));
+        }
+
+        Ok(Response::new(CronJobId { id: cron_job.id }))
+    }
+
+    async fn get_cron_jobs(&self, _req: Request<()>) -> Result<Response<CronJobs>, Status> {
+        let cron_jobs = self.storage.cron_jobs().await;
+        Ok(Response::new(CronJobs { cron_jobs }))
+    }
+
+    async fn remove_cron_job(&self, req: Request<CronJobId>) -> Result<Response<()>, Status> {
+        let id = req.into_inner().id;
+        if let Some(handle) = self.cron_handles.lock().await.remove(&id) {
+            handle.stop().await;
+        }
+        self.storage
+            .remove_cron_job(&id)
+            .await
+            .map_err(storage_error)?
+            .ok_or_else(|| Status::not_found(format!("no cron job with id `{}`", id)))?;
+
+        Ok(Response::new(()))
+    }
+
+    async fn get_job(&self, req: Request<JobId>) -> Result<Response<JobInfo>, Status> {
+        let id = req.into_inner().id;
+        let (tx, mut rx) = mpsc::channel(5);
+        for exec in self.exec_handles.lock().await.values_mut() {
+            exec.send(ExecutorCtl::ReportJob(id.clone(), tx.clone()))
+                .await
+                .unwrap();
+        }
+        drop(tx);
+
+        // only the executor owning the job answers, others drop the sender
+        match timeout(REPORT_TIMEOUT, rx.recv()).await {
+            Ok(Some(Report::Job(info))) => return Ok(Response::new(*info)),
+            Ok(Some(Report::Busy)) => {
+                return Err(Status::unavailable(format!(
+                    "job `{}` is too busy to report",
+                    id
+                )))
+            }
+            Ok(None) => {}
+            Err(_) => {
+                return Err(Status::unavailable(format!(
+                    "job `{}` didn't report in time",
+                    id
+                )))
+            }
+        }
+
+        let dead = self.storage.dead_jobs().await;
+        match dead.into_iter().find(|d| is_job(d, &id)) {
+            Some(d) => Ok(Response::new(dead_job_info(d))),
+            None => Err(Status::not_found(format!("no job with id `{}`", id))),
+        }
+    }
+
+    async fn cancel_job(&self, req: Request<JobId>) -> Result<Response<()>, Status> {
+        let id = req.into_inner().id;
+        let (tx, mut rx) = mpsc::channel(5);
+        for exec in self.exec_handles.lock().await.values_mut() {
+            exec.send(ExecutorCtl::CancelJob(id.clone(), tx.clone()))
+                .await
+                .unwrap();
+        }
+        drop(tx);
+
+        match rx.recv().await {
+            Some(_) => Ok(Response::new(())),
+            None => Err(Status::not_found(format!(
+                "no pending job with id `{}`",
+                id
+            ))),
+        }
+    }
+
+    type WatchProgressStream =
+        Pin<Box<dyn Stream<Item = Result<Progress, Status>> + Send + Sync + 'static>>;
+
+    async fn watch_progress(
+        &self,
+        req: Request<JobId>,
+    ) -> Result<Response<Self::WatchProgressStream>, Status> {
+        let id = req.into_inner().id;
+        let (tx, mut rx) = mpsc::channel(16);
+        for exec in self.exec_handles.lock().await.values_mut() {
+            exec.send(ExecutorCtl::WatchJob(id.clone(), tx.clone()))
+                .await
+                .unwrap();
+        }
+        drop(tx);
+
+        // owning task answers with current progress right away, stream ends with the job
+        let current = rx
+            .recv()
+            .await
+            .ok_or_else(|| Status::not_found(format!("no pending job with id `{}`", id)))?;
+        let updates = stream::once(async { current }).chain(rx).map(Ok);
+
+        Ok(Response::new(Box::pin(updates) as Self::WatchProgressStream))
+    }
+
+    async fn get_job_result(&self, req: Request<JobId>) -> Result<Response<JobResult>, Status> {
+        let id = req.into_inner().id;
         let (tx, mut rx) = mpsc::channel(5);
-        let mut handles = self.exec_handles.lock().await;
+        for exec in self.exec_handles.lock().await.values_mut() {
+            exec.send(ExecutorCtl::GetResult(id.clone(), tx.clone()))
+                .await
+                .unwrap();
+        }
+        drop(tx);
+
+        match rx.recv().await {
+            Some(Some(result)) => Ok(Response::new(result)),
+            Some(None) => Err(Status::failed_precondition(format!(
+                "job `{}` hasn't finished yet",
+                id
+            ))),
+            None => Err(Status::not_found(format!("no result for job `{}`", id))),
+        }
+    }
+
+    async fn await_job_result(
+        &self,
+        req: Request<JobResultQuery>,
+    ) -> Result<Response<JobResult>, Status> {
+        let query = req.into_inner();
+        let wait_for = match query.timeout {
+            Some(d) if d.seconds < 0 || d.nanos < 0 => {
+                return Err(Status::invalid_argument("`timeout` is negative"))
+            }
+            d => d.and_then(|d| Duration::try_from(d).ok()),
+        };
 
-        for (_, exec) in handles.iter_mut() {
-            exec.send(ExecutorCtl::ReportDeadJobs(tx.clone()))
+        let id = query.id;
+        let (tx, mut rx) = mpsc::channel(5);
+        for exec in self.exec_handles.lock().await.values_mut() {
+            exec.send(ExecutorCtl::AwaitResult(id.clone(), tx.clone()))
                 .await
                 .unwrap();
         }
+        drop(tx);
+
+        // sender is dropped if the job is unknown or finishes without an outcome
+        // (e.g. gets cancelled)
+        let result = match wait_for {
+            Some(dur) => timeout(dur, rx.recv()).await.map_err(|_| {
+                Status::deadline_exceeded(format!("job `{}` hasn't finished in time", id))
+            })?,
+            None => rx.recv().await,
+        };
+        result
+            .map(Response::new)
+            .ok_or_else(|| Status::not_found(format!("no result for job `{}`", id)))
+    }
+
+    type SubscribeStream =
+        Pin<Box<dyn Stream<Item = Result<Event, Status>> + Send + Sync + 'static>>;
+
+    #[instrument(name = "subscriber")]
+    async fn subscribe(
+        &self,
+        req: Request<EventFilter>,
+    ) -> Result<Response<Self::SubscribeStream>, Status> {
+        let job_names = req.into_inner().job_names;
+        let mut events = self.events.subscribe();
+        let (mut tx, rx) = mpsc::channel(100);
 
+        let forwarder = async move {
+            loop {
+                let event = match events.recv().await {
+                    Ok(event) => event,
+                    Err(RecvError::Lagged(missed)) => {
+                        warn!(message = "subscriber lagging behind", missed);
+                        continue;
+                    }
+                    Err(RecvError::Closed) => break,
+                };
+                if !job_names.is_empty() && !job_names.contains(&event.job_name) {
+                    continue;
+                }
+                if tx.send(Ok(event)).await.is_err() {
+                    // subscriber is gone
+                    break;
+                }
+            }
+        };
+        tokio::spawn(forwarder.in_current_span());
+
+        Ok(Response::new(Box::pin(rx) as Self::SubscribeStream))
+    }
+
+    async fn list_queues(&self, _: Request<()>) -> Result<Response<Queues>, Status> {
+        let mut queues: BTreeMap<String, Queue> = BTreeMap::new();
+
+        let job_names: Vec<_> = self.exec_handles.lock().await.keys().cloned().collect();
+        for name in &job_names {
+            queue(&mut queues, name);
+        }
+        for info in self.pending_jobs(None).await? {
+            let job = info.job.as_ref().unwrap();
+            let q = queue(&mut queues, &job.name);
+            q.pending += 1;
+            match info.state() {
+                JobState::Scheduled if info.try_count > 0 => q.retrying += 1,
+                JobState::Scheduled => q.scheduled += 1,
+                JobState::Waiting => q.waiting += 1,
+                JobState::Reserved => q.reserved += 1,
+                JobState::Dead => {}
+            }
+        }
+        for dead in self.storage.dead_jobs().await {
+            if let Some(job) = &dead.job {
+                queue(&mut queues, &job.name).dead += 1;
+            }
+        }
+        for connected in self.workers.lock().await.values() {
+            for name in &connected.info.job_names {
+                queue(&mut queues, name).workers += 1;
+            }
+        }
+
+        let queues = queues.into_values().collect();
+        Ok(Response::new(Queues { queues }))
+    }
+
+    async fn list_workers(&self, _: Request<()>) -> Result<Response<Workers>, Status> {
+        let mut workers: Vec<_> = self
+            .workers
+            .lock()
+            .await
+            .values()
+            .map(|connected| WorkerInfo {
+                in_flight: connected.worker.in_flight() as u32,
+                ..connected.info.clone()
+            })
+            .collect();
+        workers.sort_by_key(|w| w.connected_since.as_ref().map(|t| (t.seconds, t.nanos)));
+        Ok(Response::new(Workers { workers }))
+    }
+
+    async fn list_jobs(&self, req: Request<JobsQuery>) -> Result<Response<Jobs>, Status> {
+        let query = req.into_inner();
+        let job_name = Some(query.job_name.as_str()).filter(|n| !n.is_empty());
         let mut jobs = Vec::new();
-        for _ in 0..handles.len() {
-            let v = rx.recv().await.unwrap();
-            jobs.extend(v);
+        let wants =
+            |state: JobState| query.states.is_empty() || query.states.contains(&state.into());
+
+        if [JobState::Scheduled, JobState::Waiting, JobState::Reserved]
+            .iter()
+            .any(|s| wants(*s))
+        {
+            jobs.extend(self.pending_jobs(job_name).await?);
+        }
+        if wants(JobState::Dead) {
+            let dead = self.storage.dead_jobs().await.into_iter();
+            jobs.extend(dead.map(dead_job_info));
         }
 
-        let res = Response::new(DeadJobs { jobs });
-        Ok(res)
+        Ok(Response::new(query::jobs(jobs, &query)))
     }
 }
 
+fn queue<'a>(queues: &'a mut BTreeMap<String, Queue>, name: &str) -> &'a mut Queue {
+    queues.entry(name.to_owned()).or_insert_with(|| Queue {
+        name: name.to_owned(),
+        ..Queue::default()
+    })
+}
+
+fn dead_job_info(dead: DeadJob) -> JobInfo {
+    let mut info = JobInfo {
+        job: dead.job,
+        try_count: dead.try_count,
+        last_failure: dead.last_error,
+        failures: dead.failures,
+        ..JobInfo::default()
+    };
+    info.set_state(JobState::Dead);
+    info
+}
+
 fn parse_job_names(meta: &MetadataMap) -> Result<Vec<String>, Status> {
     let res = meta
         .get("job_names")
@@ -142,3 +783,94 @@ fn parse_job_names(meta: &MetadataMap) -> Result<Vec<String>, Status> {
         .collect();
     Ok(res)
 }
+
+fn parse_max_in_flight(meta: &MetadataMap) -> Result<usize, Status> {
+    let max_in_flight = match meta.get("max_in_flight") {
+        Some(v) => v
+            .to_str()
+            .map_err(|_| Status::invalid_argument("invalid ASCII in `max_in_flight` field"))?
+            .parse()
+            .map_err(|_| Status::invalid_argument("`max_in_flight` is not a number"))?,
+        None => 0,
+    };
+    Ok(max_in_flight)
+}
+
+/// Rejects jobs with durations that can't be waited for.
+fn validate_job(job: &Job) -> Result<(), Status> {
+    let delayed = match &job.execution_time {
+        Some(ExecutionTime::Delayed(d)) => Some(d),
+        _ => None,
+    };
+    let retry = job.retry.as_ref();
+    let durations = [
+        ("delayed", delayed),
+        ("reservation_time", job.reservation_time.as_ref()),
+        ("unique_for", job.unique_for.as_ref()),
+        (
+            "retry.base_delay",
+            retry.and_then(|r| r.base_delay.as_ref()),
+        ),
+        ("retry.max_delay", retry.and_then(|r| r.max_delay.as_ref())),
+        (
+            "misfire.tolerance",
+            job.misfire.as_ref().and_then(|m| m.tolerance.as_ref()),
+        ),
+    ];
+
+    for (field, dur) in durations.iter() {
+        if let Some(dur) = dur {
+            // `TryFrom` lets zero seconds with negative nanos through
+            if dur.seconds < 0 || dur.nanos < 0 {
+                return Err(Status::invalid_argument(format!(
+                    "`{}` of job `{}` is negative",
+                    field, job.id
+                )));
+            }
+            if dur.seconds as u64 >= MAX_DURATION.as_secs() {
+                return Err(Status::invalid_argument(format!(
+                    "`{}` of job `{}` exceeds {} seconds",
+                    field,
+                    job.id,
+                    MAX_DURATION.as_secs()
+                )));
+            }
+        }
+    }
+
+    if let Some(ExecutionTime::Scheduled(timestamp)) = &job.execution_time {
+        let too_far = match SystemTime::try_from(timestamp.clone()) {
+            Ok(t) => t
+                .duration_since(SystemTime::now())
+                .is_ok_and(|d| d >= MAX_DURATION),
+            Err(_) => true,
+        };
+        if too_far {
+            return Err(Status::invalid_argument(format!(
+                "`scheduled` of job `{}` is more than {} seconds away",
+                job.id,
+                MAX_DURATION.as_secs()
+            )));
+        }
+    }
+
+    // try count is kept in a byte
+    if let Some(r) = retry {
+        if r.max_attempts > u8::MAX as u32 {
+            return Err(Status::invalid_argument(format!(
+                "`retry.max_attempts` of job `{}` exceeds {}",
+                job.id,
+                u8::MAX
+            )));
+        }
+    }
+    Ok(())
+}
+
+fn storage_error(e: io::Error) -> Status {
+    Status::unavailable(format!("storage failure: {}", e))
+}
+
+fn is_job(dead: &DeadJob, job_id: &str) -> bool {
+    matches!(&dead.job, Some(j) if j.id == job_id)
+}
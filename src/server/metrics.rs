@@ -0,0 +1,125 @@
+use hyper::header::CONTENT_TYPE;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, Server, StatusCode};
+use prometheus::{
+    exponential_buckets, Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGauge,
+    IntGaugeVec, Opts, Registry, TextEncoder,
+};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use tracing::{info, warn};
+
+/// Prometheus metrics shared by executors and tasks, labeled by job name where it makes sense.
+#[derive(Clone, Debug)]
+pub struct Metrics {
+    registry: Registry,
+    pub enqueued: IntCounterVec,
+    pub succeeded: IntCounterVec,
+    /// Every failed attempt, including expired reservations.
+    pub failed: IntCounterVec,
+    pub died: IntCounterVec,
+    /// Jobs that aren't done yet.
+    pub queue_depth: IntGaugeVec,
+    /// Tasks waiting for a worker with a free slot.
+    pub starving: IntGaugeVec,
+    /// From the moment job is ready to run until it's sent to a worker.
+    pub time_in_queue: HistogramVec,
+    /// From the moment job is sent to a worker until its `JobResult` arrives.
+    pub execution_time: HistogramVec,
+    pub connected_workers: IntGauge,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let registry = Registry::new_custom(Some("lakh".to_owned()), None).unwrap();
+        let counter = |name: &str, help: &str| {
+            let counter = IntCounterVec::new(Opts::new(name, help), &["job_name"]).unwrap();
+            registry.register(Box::new(counter.clone())).unwrap();
+            counter
+        };
+        let gauge = |name: &str, help: &str| {
+            let gauge = IntGaugeVec::new(Opts::new(name, help), &["job_name"]).unwrap();
+            registry.register(Box::new(gauge.clone())).unwrap();
+            gauge
+        };
+        // 10ms up to about 45 minutes
+        let histogram = |name: &str, help: &str| {
+            let opts =
+                HistogramOpts::new(name, help).buckets(exponential_buckets(0.01, 4.0, 10).unwrap());
+            let histogram = HistogramVec::new(opts, &["job_name"]).unwrap();
+            registry.register(Box::new(histogram.clone())).unwrap();
+            histogram
+        };
+
+        let connected_workers =
+            IntGauge::new("connected_workers", "Workers connected to `Join`.").unwrap();
+        registry
+            .register(Box::new(connected_workers.clone()))
+            .unwrap();
+
+        Self {
+            enqueued: counter("jobs_enqueued_total", "Jobs accepted for processing."),
+            succeeded: counter("jobs_succeeded_total", "Jobs done successfully."),
+            failed: counter("jobs_failed_total", "Failed attempts of jobs."),
+            died: counter("jobs_died_total", "Jobs moved to dead jobs."),
+            queue_depth: gauge("queue_depth", "Jobs that aren't done yet."),
+            starving: gauge("starving_tasks", "Jobs waiting for a free worker."),
+            time_in_queue: histogram(
+                "time_in_queue_seconds",
+                "Time from job being ready to run until it's sent to a worker.",
+            ),
+            execution_time: histogram(
+                "execution_seconds",
+                "Time from job being sent to a worker until its result arrives.",
+            ),
+            connected_workers,
+            registry,
+        }
+    }
+
+    fn render(&self) -> Response<Body> {
+        let encoder = TextEncoder::new();
+        let mut buf = Vec::new();
+        if let Err(e) = encoder.encode(&self.registry.gather(), &mut buf) {
+            warn!(message = "failed to encode metrics", %e);
+            return status(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+        Response::builder()
+            .header(CONTENT_TYPE, encoder.format_type())
+            .body(Body::from(buf))
+            .unwrap()
+    }
+}
+
+/// Serves metrics in Prometheus text format at `/metrics`.
+pub fn spawn_server(addr: SocketAddr, metrics: Metrics) -> Result<(), hyper::Error> {
+    let builder = Server::try_bind(&addr)?;
+    let make_svc = make_service_fn(move |_| {
+        let metrics = metrics.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                let res = match req.uri().path() {
+                    "/metrics" => metrics.render(),
+                    _ => status(StatusCode::NOT_FOUND),
+                };
+                async move { Ok::<_, Infallible>(res) }
+            }))
+        }
+    });
+
+    let server = async move {
+        info!("serving metrics on {}", addr);
+        if let Err(e) = builder.serve(make_svc).await {
+            warn!(message = "metrics server failed", %e);
+        }
+    };
+    tokio::spawn(server);
+    Ok(())
+}
+
+fn status(code: StatusCode) -> Response<Body> {
+    Response::builder()
+        .status(code)
+        .body(Body::empty())
+        .unwrap()
+}
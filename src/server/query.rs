@@ -0,0 +1,111 @@
+// tonic hands out `Status` as is, boxing it in every helper isn't worth it
+#![allow(clippy::result_large_err)]
+
+use prost_types::Timestamp;
+use tonic::Status;
+
+use crate::pb::{DeadJob, DeadJobs, DeadJobsQuery, JobInfo, Jobs, JobsQuery};
+
+const DEFAULT_PAGE_SIZE: usize = 100;
+
+/// Position of a dead job in the listing, they're ordered by time of death and id.
+type Cursor = (i64, i32, String);
+
+/// Applies filters and pagination of `query` to `dead`.
+pub fn dead_jobs(dead: Vec<DeadJob>, query: &DeadJobsQuery) -> Result<DeadJobs, Status> {
+    let after = match query.cursor.as_str() {
+        "" => None,
+        c => Some(parse_cursor(c)?),
+    };
+    let limit = match query.limit {
+        0 => DEFAULT_PAGE_SIZE,
+        n => n as usize,
+    };
+
+    let mut page: Vec<_> = dead
+        .into_iter()
+        .filter(|d| matches(d, query))
+        .map(|d| (cursor(&d), d))
+        .filter(|(c, _)| after.as_ref().is_none_or(|after| c > after))
+        .collect();
+    page.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+    let next_cursor = match page.get(limit) {
+        Some(_) => format_cursor(&page[limit - 1].0),
+        None => String::new(),
+    };
+    let jobs = page.into_iter().take(limit).map(|(_, d)| d).collect();
+    Ok(DeadJobs { jobs, next_cursor })
+}
+
+/// Applies filters and pagination of `query` to `jobs`, they're ordered by id
+/// which is also used as the cursor.
+pub fn jobs(jobs: Vec<JobInfo>, query: &JobsQuery) -> Jobs {
+    let limit = match query.limit {
+        0 => DEFAULT_PAGE_SIZE,
+        n => n as usize,
+    };
+
+    let mut page: Vec<_> = jobs
+        .into_iter()
+        .filter(|info| query.states.is_empty() || query.states.contains(&info.state))
+        .map(|info| {
+            (
+                info.job.as_ref().map_or(String::new(), |j| j.id.clone()),
+                info,
+            )
+        })
+        .filter(|(id, info)| {
+            let job_name = info.job.as_ref().map_or("", |j| j.name.as_str());
+            (query.job_name.is_empty() || query.job_name == job_name)
+                && (query.cursor.is_empty() || *id > query.cursor)
+        })
+        .collect();
+    page.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+    let next_cursor = match page.get(limit) {
+        Some(_) => page[limit - 1].0.clone(),
+        None => String::new(),
+    };
+    let jobs = page.into_iter().take(limit).map(|(_, info)| info).collect();
+    Jobs { jobs, next_cursor }
+}
+
+fn matches(dead: &DeadJob, query: &DeadJobsQuery) -> bool {
+    let job_name = dead.job.as_ref().map_or("", |j| j.name.as_str());
+    let died_at = seconds_nanos(&dead.died_at);
+
+    (query.job_name.is_empty() || query.job_name == job_name)
+        && (query.died_after.is_none() || died_at >= seconds_nanos(&query.died_after))
+        && (query.died_before.is_none() || died_at < seconds_nanos(&query.died_before))
+        && (query.reasons.is_empty() || query.reasons.contains(&dead.reason))
+}
+
+fn seconds_nanos(t: &Option<Timestamp>) -> (i64, i32) {
+    t.as_ref().map_or((0, 0), |t| (t.seconds, t.nanos))
+}
+
+fn cursor(dead: &DeadJob) -> Cursor {
+    let (seconds, nanos) = seconds_nanos(&dead.died_at);
+    let id = dead.job.as_ref().map_or("", |j| j.id.as_str());
+    (seconds, nanos, id.to_owned())
+}
+
+fn format_cursor((seconds, nanos, id): &Cursor) -> String {
+    format!("{}.{}.{}", seconds, nanos, id)
+}
+
+fn parse_cursor(cursor: &str) -> Result<Cursor, Status> {
+    let invalid = || Status::invalid_argument("invalid cursor");
+    let mut parts = cursor.splitn(3, '.');
+    let seconds = parts
+        .next()
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(invalid)?;
+    let nanos = parts
+        .next()
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(invalid)?;
+    let id = parts.next().ok_or_else(invalid)?;
+    Ok((seconds, nanos, id.to_owned()))
+}
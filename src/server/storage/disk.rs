@@ -0,0 +1,450 @@
+use async_trait::async_trait;
+use prost::Message;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use tokio::fs::{self, File};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+use tracing::{error, info, warn};
+
+use super::{DeadJobFilter, Storage};
+use crate::pb::job::ExecutionTime;
+use crate::pb::wal::{record::Entry, Pending, Record};
+use crate::pb::{CronJob, DeadJob, Job};
+
+/// Log isn't compacted before it reaches this size.
+const MIN_COMPACT_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Storage backed by an append-only log of job state transitions.
+///
+/// Every record is a length delimited `wal.Record`. On startup, and whenever it
+/// doubles in size since then, the log is replayed and rewritten so that it only
+/// contains jobs that are still alive.
+#[derive(Debug)]
+pub struct Disk {
+    path: PathBuf,
+    log: Mutex<Log>,
+    dead: Mutex<Vec<DeadJob>>,
+    cron_jobs: Mutex<HashMap<String, CronJob>>,
+    recovered: Mutex<Vec<(Job, u8)>>,
+}
+
+#[derive(Debug)]
+struct Log {
+    file: File,
+    len: u64,
+    compact_at: u64,
+}
+
+impl Log {
+    fn new(file: File, len: u64) -> Self {
+        Self {
+            file,
+            len,
+            compact_at: (len * 2).max(MIN_COMPACT_SIZE),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Replayed {
+    pending: HashMap<String, Pending>,
+    dead: Vec<DeadJob>,
+    cron_jobs: HashMap<String, CronJob>,
+}
+
+impl Disk {
+    pub async fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+        let buf = match fs::read(path).await {
+            Ok(buf) => buf,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        let replayed = replay(&buf);
+        let compacted = compact(&replayed);
+        let file = rewrite(path, &compacted).await?;
+        let Replayed {
+            pending,
+            dead,
+            cron_jobs,
+        } = replayed;
+
+        info!(
+            message = "replayed write-ahead log",
+            pending = pending.len(),
+            dead = dead.len(),
+            cron_jobs = cron_jobs.len()
+        );
+
+        let now = SystemTime::now();
+        let pending = pending
+            .into_values()
+            .filter_map(|p| {
+                let mut job = p.job?;
+                let not_before = p
+                    .not_before
+                    .and_then(|t| SystemTime::try_from(t).ok())
+                    .unwrap_or(now);
+                // keeps track of how late the job is, see `MisfirePolicy`
+                job.execution_time = Some(ExecutionTime::Scheduled(not_before.into()));
+                Some((job, p.try_count as u8))
+            })
+            .collect();
+
+        Ok(Self {
+            path: path.to_owned(),
+            log: Mutex::new(Log::new(file, compacted.len() as u64)),
+            dead: Mutex::new(dead),
+            cron_jobs: Mutex::new(cron_jobs),
+            recovered: Mutex::new(pending),
+        })
+    }
+
+    async fn append(&self, entry: Entry) -> io::Result<()> {
+        let mut buf = Vec::new();
+        encode(entry, &mut buf);
+        self.append_encoded(&buf).await
+    }
+
+    async fn append_encoded(&self, buf: &[u8]) -> io::Result<()> {
+        let mut log = self.log.lock().await;
+        if let Err(e) = write(&mut log.file, buf).await {
+            error!(message = "failed to append to write-ahead log", %e);
+            return Err(e);
+        }
+        log.len += buf.len() as u64;
+
+        // every retry appends the whole job again, without this the log grows forever
+        if log.len >= log.compact_at {
+            if let Err(e) = self.compact(&mut log).await {
+                // the log is still whole, it's just bigger than it could be
+                warn!(message = "failed to compact write-ahead log", %e);
+                log.compact_at = log.len * 2;
+            }
+        }
+        Ok(())
+    }
+
+    /// Rewrites the log with only what's alive, callers must hold the lock of `log`.
+    async fn compact(&self, log: &mut Log) -> io::Result<()> {
+        let buf = fs::read(&self.path).await?;
+        let compacted = compact(&replay(&buf));
+        let file = rewrite(&self.path, &compacted).await?;
+        info!(
+            message = "compacted write-ahead log",
+            before = log.len,
+            after = compacted.len()
+        );
+        *log = Log::new(file, compacted.len() as u64);
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Storage for Disk {
+    async fn enqueue(&self, job: &Job) -> io::Result<()> {
+        self.reschedule(job, 0).await
+    }
+
+    async fn reschedule(&self, job: &Job, try_count: u8) -> io::Result<()> {
+        let not_before = calc_not_before(&job.execution_time);
+        let pending = Pending {
+            job: Some(job.clone()),
+            try_count: try_count as u32,
+            not_before: Some(not_before.into()),
+        };
+        self.append(Entry::Pending(pending)).await
+    }
+
+    async fn finish(&self, job_id: &str) -> io::Result<()> {
+        self.append(Entry::Finished(job_id.to_owned())).await
+    }
+
+    async fn bury(&self, dead: DeadJob) -> io::Result<()> {
+        let res = self.append(Entry::Died(dead.clone())).await;
+        self.dead.lock().await.push(dead);
+        res
+    }
+
+    async fn dead_jobs(&self) -> Vec<DeadJob> {
+        self.dead.lock().await.clone()
+    }
+
+    async fn remove_dead_jobs(&self, filter: &DeadJobFilter<'_>) -> io::Result<Vec<DeadJob>> {
+        let mut dead = self.dead.lock().await;
+        let mut buf = Vec::new();
+        for d in dead.iter().filter(|d| filter(d)) {
+            if let Some(job) = &d.job {
+                encode(Entry::DeadJobRemoved(job.id.clone()), &mut buf);
+            }
+        }
+        // dead jobs stay where they are unless their removal is durable
+        if !buf.is_empty() {
+            self.append_encoded(&buf).await?;
+        }
+        let (removed, kept) = dead.drain(..).partition(|d| filter(d));
+        *dead = kept;
+        Ok(removed)
+    }
+
+    async fn recover(&self) -> Vec<(Job, u8)> {
+        std::mem::take(&mut *self.recovered.lock().await)
+    }
+
+    async fn register_cron_job(&self, cron_job: &CronJob) -> io::Result<()> {
+        self.append(Entry::CronJobRegistered(cron_job.clone()))
+            .await?;
+        let mut cron_jobs = self.cron_jobs.lock().await;
+        cron_jobs.insert(cron_job.id.clone(), cron_job.clone());
+        Ok(())
+    }
+
+    async fn remove_cron_job(&self, cron_id: &str) -> io::Result<Option<CronJob>> {
+        let mut cron_jobs = self.cron_jobs.lock().await;
+        if !cron_jobs.contains_key(cron_id) {
+            return Ok(None);
+        }
+        self.append(Entry::CronJobRemoved(cron_id.to_owned()))
+            .await?;
+        Ok(cron_jobs.remove(cron_id))
+    }
+
+    async fn cron_jobs(&self) -> Vec<CronJob> {
+        self.cron_jobs.lock().await.values().cloned().collect()
+    }
+}
+
+fn replay(mut buf: &[u8]) -> Replayed {
+    let mut replayed = Replayed::default();
+
+    while !buf.is_empty() {
+        let record = match Record::decode_length_delimited(&mut buf) {
+            Ok(r) => r,
+            Err(e) => {
+                // most likely a torn write from a crash, nothing after it can be trusted
+                warn!(message = "truncated write-ahead log", %e);
+                break;
+            }
+        };
+        match record.entry {
+            Some(Entry::Pending(p)) => {
+                if let Some(job) = &p.job {
+                    replayed.pending.insert(job.id.clone(), p);
+                }
+            }
+            Some(Entry::Finished(id)) => {
+                replayed.pending.remove(&id);
+            }
+            Some(Entry::Died(d)) => {
+                if let Some(job) = &d.job {
+                    replayed.pending.remove(&job.id);
+                }
+                replayed.dead.push(d);
+            }
+            Some(Entry::DeadJobRemoved(id)) => {
+                replayed
+                    .dead
+                    .retain(|d| !matches!(&d.job, Some(j) if j.id == id));
+            }
+            Some(Entry::CronJobRegistered(c)) => {
+                replayed.cron_jobs.insert(c.id.clone(), c);
+            }
+            Some(Entry::CronJobRemoved(id)) => {
+                replayed.cron_jobs.remove(&id);
+            }
+            None => {}
+        }
+    }
+
+    replayed
+}
+
+/// Encodes just enough records to replay `replayed` again.
+fn compact(replayed: &Replayed) -> Vec<u8> {
+    let mut buf = Vec::new();
+    for p in replayed.pending.values() {
+        encode(Entry::Pending(p.clone()), &mut buf);
+    }
+    for d in &replayed.dead {
+        encode(Entry::Died(d.clone()), &mut buf);
+    }
+    for c in replayed.cron_jobs.values() {
+        encode(Entry::CronJobRegistered(c.clone()), &mut buf);
+    }
+    buf
+}
+
+/// Atomically replaces the log at `path` with `buf`, returns the file to keep appending to.
+async fn rewrite(path: &Path, buf: &[u8]) -> io::Result<File> {
+    let tmp = path.with_extension("tmp");
+    let mut file = File::create(&tmp).await?;
+    write(&mut file, buf).await?;
+    fs::rename(&tmp, path).await?;
+    Ok(file)
+}
+
+fn encode(entry: Entry, buf: &mut Vec<u8>) {
+    let record = Record { entry: Some(entry) };
+    // encoding into a `Vec` can't run out of space
+    record.encode_length_delimited(buf).unwrap();
+}
+
+async fn write(file: &mut File, buf: &[u8]) -> io::Result<()> {
+    file.write_all(buf).await?;
+    file.sync_data().await
+}
+
+fn calc_not_before(exec_time: &Option<ExecutionTime>) -> SystemTime {
+    let now = SystemTime::now();
+    match exec_time {
+        Some(ExecutionTime::Scheduled(timestamp)) => {
+            SystemTime::try_from(timestamp.clone()).unwrap_or(now)
+        }
+        Some(ExecutionTime::Delayed(dur)) => Duration::try_from(dur.clone())
+            .ok()
+            .and_then(|dur| now.checked_add(dur))
+            .unwrap_or(now),
+        Some(ExecutionTime::Immediate(_)) | None => now,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn job(id: &str) -> Job {
+        Job {
+            id: id.to_owned(),
+            name: "add".to_owned(),
+            ..Job::default()
+        }
+    }
+
+    fn pending(id: &str, try_count: u32) -> Entry {
+        Entry::Pending(Pending {
+            job: Some(job(id)),
+            try_count,
+            not_before: None,
+        })
+    }
+
+    fn dead(id: &str) -> Entry {
+        Entry::Died(DeadJob {
+            job: Some(job(id)),
+            ..DeadJob::default()
+        })
+    }
+
+    fn cron_job(id: &str) -> CronJob {
+        CronJob {
+            id: id.to_owned(),
+            schedule: "0 * * * * *".to_owned(),
+            template: Some(job("")),
+            ..CronJob::default()
+        }
+    }
+
+    fn log(entries: Vec<Entry>) -> Vec<u8> {
+        let mut buf = Vec::new();
+        for entry in entries {
+            encode(entry, &mut buf);
+        }
+        buf
+    }
+
+    fn dead_ids(replayed: &Replayed) -> Vec<&str> {
+        replayed
+            .dead
+            .iter()
+            .filter_map(|d| d.job.as_ref())
+            .map(|j| j.id.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn finished_job_is_dropped() {
+        let replayed = replay(&log(vec![
+            pending("a", 0),
+            pending("b", 0),
+            pending("a", 1),
+            Entry::Finished("a".to_owned()),
+        ]));
+        assert_eq!(replayed.pending.len(), 1);
+        assert_eq!(replayed.pending["b"].try_count, 0);
+    }
+
+    #[test]
+    fn latest_attempt_wins() {
+        let replayed = replay(&log(vec![
+            pending("a", 0),
+            pending("a", 1),
+            pending("a", 2),
+        ]));
+        assert_eq!(replayed.pending["a"].try_count, 2);
+    }
+
+    #[test]
+    fn removed_dead_job_is_dropped() {
+        let replayed = replay(&log(vec![
+            pending("a", 0),
+            pending("b", 0),
+            dead("a"),
+            dead("b"),
+            Entry::DeadJobRemoved("a".to_owned()),
+        ]));
+        assert!(replayed.pending.is_empty());
+        assert_eq!(dead_ids(&replayed), vec!["b"]);
+    }
+
+    #[test]
+    fn truncated_record_is_tolerated() {
+        let mut buf = log(vec![pending("a", 0), pending("b", 0)]);
+        let whole = buf.len();
+        encode(pending("c", 0), &mut buf);
+        buf.truncate(whole + 3);
+
+        let replayed = replay(&buf);
+        assert_eq!(replayed.pending.len(), 2);
+        assert!(!replayed.pending.contains_key("c"));
+    }
+
+    #[test]
+    fn cron_jobs_are_replayed() {
+        let replayed = replay(&log(vec![
+            Entry::CronJobRegistered(cron_job("a")),
+            Entry::CronJobRegistered(cron_job("b")),
+            Entry::CronJobRemoved("a".to_owned()),
+            Entry::CronJobRegistered(CronJob {
+                schedule: "0 0 * * * *".to_owned(),
+                ..cron_job("b")
+            }),
+        ]));
+        assert_eq!(replayed.cron_jobs.len(), 1);
+        assert_eq!(replayed.cron_jobs["b"].schedule, "0 0 * * * *");
+    }
+
+    #[test]
+    fn compacted_log_replays_the_same() {
+        let buf = log(vec![
+            pending("a", 0),
+            pending("b", 0),
+            pending("b", 1),
+            Entry::Finished("a".to_owned()),
+            pending("c", 0),
+            dead("c"),
+            Entry::CronJobRegistered(cron_job("x")),
+        ]);
+        let replayed = replay(&buf);
+        let compacted = compact(&replayed);
+        assert!(compacted.len() < buf.len());
+
+        let again = replay(&compacted);
+        assert_eq!(again.pending, replayed.pending);
+        assert_eq!(again.dead, replayed.dead);
+        assert_eq!(again.cron_jobs, replayed.cron_jobs);
+    }
+}
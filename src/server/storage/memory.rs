@@ -0,0 +1,63 @@
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::io;
+use tokio::sync::Mutex;
+
+use super::{DeadJobFilter, Storage};
+use crate::pb::{CronJob, DeadJob, Job};
+
+/// Keeps only dead jobs and cron jobs, everything is lost on restart.
+#[derive(Debug, Default)]
+pub struct Memory {
+    dead: Mutex<Vec<DeadJob>>,
+    cron_jobs: Mutex<HashMap<String, CronJob>>,
+}
+
+#[async_trait]
+impl Storage for Memory {
+    async fn enqueue(&self, _job: &Job) -> io::Result<()> {
+        Ok(())
+    }
+
+    async fn reschedule(&self, _job: &Job, _try_count: u8) -> io::Result<()> {
+        Ok(())
+    }
+
+    async fn finish(&self, _job_id: &str) -> io::Result<()> {
+        Ok(())
+    }
+
+    async fn bury(&self, dead: DeadJob) -> io::Result<()> {
+        self.dead.lock().await.push(dead);
+        Ok(())
+    }
+
+    async fn dead_jobs(&self) -> Vec<DeadJob> {
+        self.dead.lock().await.clone()
+    }
+
+    async fn remove_dead_jobs(&self, filter: &DeadJobFilter<'_>) -> io::Result<Vec<DeadJob>> {
+        let mut dead = self.dead.lock().await;
+        let (removed, kept) = dead.drain(..).partition(|d| filter(d));
+        *dead = kept;
+        Ok(removed)
+    }
+
+    async fn recover(&self) -> Vec<(Job, u8)> {
+        Vec::new()
+    }
+
+    async fn register_cron_job(&self, cron_job: &CronJob) -> io::Result<()> {
+        let mut cron_jobs = self.cron_jobs.lock().await;
+        cron_jobs.insert(cron_job.id.clone(), cron_job.clone());
+        Ok(())
+    }
+
+    async fn remove_cron_job(&self, cron_id: &str) -> io::Result<Option<CronJob>> {
+        Ok(self.cron_jobs.lock().await.remove(cron_id))
+    }
+
+    async fn cron_jobs(&self) -> Vec<CronJob> {
+        self.cron_jobs.lock().await.values().cloned().collect()
+    }
+}
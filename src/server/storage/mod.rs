@@ -0,0 +1,127 @@
+use async_trait::async_trait;
+use std::collections::HashSet;
+use std::convert::TryFrom;
+use std::fmt::Debug;
+use std::io;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::time::interval;
+use tracing::{info, warn};
+
+use crate::pb::{CronJob, DeadJob, Job};
+use crate::{DeadJobsConfig, StorageConfig};
+
+mod disk;
+mod memory;
+
+pub use disk::Disk;
+pub use memory::Memory;
+
+pub type DeadJobFilter<'a> = dyn Fn(&DeadJob) -> bool + Send + Sync + 'a;
+
+/// Keeps track of every job the server knows about.
+///
+/// `Executor` and `Task` report each state transition of a job here so that
+/// backends can decide how (and whether) to make it durable. Methods changing
+/// state fail if the change couldn't be made durable.
+#[async_trait]
+pub trait Storage: Debug + Send + Sync {
+    /// Called once a job is accepted from a producer.
+    async fn enqueue(&self, job: &Job) -> io::Result<()>;
+
+    /// Called whenever a job is waiting for its next attempt.
+    async fn reschedule(&self, job: &Job, try_count: u8) -> io::Result<()>;
+
+    /// Called once a job is done and can be forgotten.
+    async fn finish(&self, job_id: &str) -> io::Result<()>;
+
+    /// Called once a job has failed for the last time. Dead job is kept
+    /// around until restart even if it couldn't be made durable.
+    async fn bury(&self, dead: DeadJob) -> io::Result<()>;
+
+    async fn dead_jobs(&self) -> Vec<DeadJob>;
+
+    /// Forgets dead jobs matching `filter` and returns them.
+    async fn remove_dead_jobs(&self, filter: &DeadJobFilter<'_>) -> io::Result<Vec<DeadJob>>;
+
+    /// Returns jobs (along with their try count) that were pending when
+    /// the server last stopped.
+    async fn recover(&self) -> Vec<(Job, u8)>;
+
+    async fn register_cron_job(&self, cron_job: &CronJob) -> io::Result<()>;
+
+    async fn remove_cron_job(&self, cron_id: &str) -> io::Result<Option<CronJob>>;
+
+    async fn cron_jobs(&self) -> Vec<CronJob>;
+}
+
+pub async fn open(config: &StorageConfig) -> io::Result<Arc<dyn Storage>> {
+    let storage: Arc<dyn Storage> = match config {
+        StorageConfig::Memory => Arc::new(Memory::default()),
+        StorageConfig::Disk { path } => Arc::new(Disk::open(path).await?),
+    };
+    Ok(storage)
+}
+
+/// Periodically drops dead jobs that are past their retention limits.
+pub fn spawn_reaper(storage: Arc<dyn Storage>, config: DeadJobsConfig) {
+    if config.limit.is_none() && config.ttl.is_none() {
+        return;
+    }
+
+    tokio::spawn(async move {
+        let mut tick = interval(Duration::from_secs(10));
+        loop {
+            tick.tick().await;
+            reap(storage.as_ref(), &config).await;
+        }
+    });
+}
+
+async fn reap(storage: &dyn Storage, config: &DeadJobsConfig) {
+    if let Err(e) = try_reap(storage, config).await {
+        warn!(message = "failed to reap dead jobs", %e);
+    }
+}
+
+async fn try_reap(storage: &dyn Storage, config: &DeadJobsConfig) -> io::Result<()> {
+    let mut reaped = 0;
+
+    let ttl = config.ttl.map(Duration::from_secs);
+    if let Some(deadline) = ttl.and_then(|ttl| SystemTime::now().checked_sub(ttl)) {
+        reaped += storage
+            .remove_dead_jobs(&|d| died_at(d) < deadline)
+            .await?
+            .len();
+    }
+
+    if let Some(limit) = config.limit {
+        let mut dead = storage.dead_jobs().await;
+        if dead.len() > limit {
+            dead.sort_by_key(died_at);
+            let excess = dead.len() - limit;
+            let oldest: HashSet<_> = dead
+                .into_iter()
+                .take(excess)
+                .filter_map(|d| d.job)
+                .map(|j| j.id)
+                .collect();
+            reaped += storage
+                .remove_dead_jobs(&|d| matches!(&d.job, Some(j) if oldest.contains(&j.id)))
+                .await?
+                .len();
+        }
+    }
+
+    if reaped > 0 {
+        info!(message = "reaped dead jobs", count = reaped);
+    }
+    Ok(())
+}
+
+fn died_at(dead: &DeadJob) -> SystemTime {
+    dead.died_at
+        .clone()
+        .and_then(|t| SystemTime::try_from(t).ok())
+        .unwrap_or(SystemTime::UNIX_EPOCH)
+}
@@ -0,0 +1,173 @@
+use rand::distributions::WeightedIndex;
+use rand::prelude::*;
+use serde::Deserialize;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::hash::{Hash, Hasher};
+
+use crate::worker::{Worker, WorkerId};
+
+/// Decides which worker gets the next job.
+pub trait Strategy: Debug + Send {
+    /// Picks a worker with a free slot for a job with given affinity key.
+    fn select<'a>(
+        &mut self,
+        workers: &'a HashMap<WorkerId, Worker>,
+        key: &str,
+    ) -> Option<&'a Worker>;
+}
+
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum StrategyKind {
+    #[default]
+    Random,
+    RoundRobin,
+    LeastOutstanding,
+    Weighted,
+    ConsistentHash,
+}
+
+impl StrategyKind {
+    pub fn build(self) -> Box<dyn Strategy> {
+        match self {
+            Self::Random => Box::new(Random),
+            Self::RoundRobin => Box::new(RoundRobin::default()),
+            Self::LeastOutstanding => Box::new(LeastOutstanding),
+            Self::Weighted => Box::new(Weighted),
+            Self::ConsistentHash => Box::new(ConsistentHash::default()),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Random;
+
+impl Strategy for Random {
+    fn select<'a>(
+        &mut self,
+        workers: &'a HashMap<WorkerId, Worker>,
+        _key: &str,
+    ) -> Option<&'a Worker> {
+        workers
+            .values()
+            .filter(|w| w.has_capacity())
+            .choose(&mut rand::thread_rng())
+    }
+}
+
+/// Cycles through workers in order of their ids.
+#[derive(Debug, Default)]
+pub struct RoundRobin {
+    last: Option<WorkerId>,
+}
+
+impl Strategy for RoundRobin {
+    fn select<'a>(
+        &mut self,
+        workers: &'a HashMap<WorkerId, Worker>,
+        _key: &str,
+    ) -> Option<&'a Worker> {
+        let mut available: Vec<_> = workers.values().filter(|w| w.has_capacity()).collect();
+        available.sort_by(|a, b| a.id.cmp(&b.id));
+
+        let w = match &self.last {
+            Some(last) => available
+                .iter()
+                .find(|w| &w.id > last)
+                .or_else(|| available.first()),
+            None => available.first(),
+        }
+        .copied()?;
+        self.last = Some(w.id.clone());
+        Some(w)
+    }
+}
+
+/// Picks worker with the fewest jobs in flight.
+#[derive(Debug)]
+pub struct LeastOutstanding;
+
+impl Strategy for LeastOutstanding {
+    fn select<'a>(
+        &mut self,
+        workers: &'a HashMap<WorkerId, Worker>,
+        _key: &str,
+    ) -> Option<&'a Worker> {
+        workers
+            .values()
+            .filter(|w| w.has_capacity())
+            .min_by_key(|w| w.in_flight())
+    }
+}
+
+/// Picks random worker with probability proportional to its `max_in_flight`,
+/// workers without a limit have weight of 1.
+#[derive(Debug)]
+pub struct Weighted;
+
+impl Strategy for Weighted {
+    fn select<'a>(
+        &mut self,
+        workers: &'a HashMap<WorkerId, Worker>,
+        _key: &str,
+    ) -> Option<&'a Worker> {
+        let available: Vec<_> = workers.values().filter(|w| w.has_capacity()).collect();
+        let weights = available.iter().map(|w| w.max_in_flight().max(1));
+        let dist = WeightedIndex::new(weights).ok()?;
+        Some(available[dist.sample(&mut rand::thread_rng())])
+    }
+}
+
+/// Sends jobs with the same key to the same worker as long as it has free slots,
+/// otherwise falls back to the next worker on the hash ring.
+#[derive(Debug, Default)]
+pub struct ConsistentHash {
+    members: Vec<WorkerId>,
+    ring: Vec<(u64, WorkerId)>,
+}
+
+const VIRTUAL_NODES: usize = 64;
+
+impl ConsistentHash {
+    fn rebuild(&mut self, workers: &HashMap<WorkerId, Worker>) {
+        let mut members: Vec<_> = workers.keys().cloned().collect();
+        members.sort();
+        if members == self.members {
+            return;
+        }
+
+        self.ring = members
+            .iter()
+            .flat_map(|id| (0..VIRTUAL_NODES).map(move |n| (hash(&(id, n)), id.clone())))
+            .collect();
+        self.ring.sort();
+        self.members = members;
+    }
+}
+
+impl Strategy for ConsistentHash {
+    fn select<'a>(
+        &mut self,
+        workers: &'a HashMap<WorkerId, Worker>,
+        key: &str,
+    ) -> Option<&'a Worker> {
+        self.rebuild(workers);
+
+        let start = self.ring.partition_point(|(h, _)| *h < hash(&key));
+        self.ring
+            .iter()
+            .cycle()
+            .skip(start)
+            .take(self.ring.len())
+            .filter_map(|(_, id)| workers.get(id))
+            .find(|w| w.has_capacity())
+    }
+}
+
+fn hash(v: &impl Hash) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    v.hash(&mut hasher);
+    hasher.finish()
+}
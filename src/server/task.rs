@@ -1,23 +1,68 @@
-use rand::Rng;
+use std::convert::TryFrom;
+use std::sync::Arc;
 use std::time::{Duration, SystemTime};
 use tokio::sync::mpsc;
-use tokio::time::delay_for;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::time::{delay_for, Instant};
 use tracing::{info, instrument, warn};
 use tracing_futures::Instrument;
 
+use crate::backoff::Backoff;
+use crate::events::Events;
 use crate::executor::ExecutorCtl;
+use crate::metrics::Metrics;
 use crate::pb::job::ExecutionTime;
-use crate::pb::Job;
+use crate::pb::{
+    DeadJob, Event, EventKind, Failure, FailureReason, Job, JobInfo, JobResult, JobState,
+    JobStatus, MisfireAction, Progress,
+};
+use crate::storage::Storage;
+use crate::worker::WorkerId;
 
 #[derive(Debug)]
 pub enum TaskCtl {
-    Retry,
-    Terminate,
+    /// Worker reported a failure.
+    Retry(Failure),
+    /// Worker reported a failure that won't go away with retries.
+    GiveUp(Failure),
+    /// Worker reported success.
+    Terminate(JobResult),
+    /// Worker holding the reservation is gone.
+    WorkerLost(WorkerId),
+    /// Worker wants to hold the reservation for longer, job's reservation time if `None`.
+    Touch(WorkerId, Option<Duration>),
+    /// Worker reported how far it got.
+    Progress(WorkerId, Progress),
+    Report(mpsc::Sender<Report>),
+    /// Sends current progress and every update to given channel.
+    Watch(mpsc::Sender<Progress>),
+    /// Job got cancelled, stop wherever it is.
+    Cancel,
+}
+
+/// Answer to a request for the state of a job.
+#[derive(Debug)]
+pub enum Report {
+    Job(Box<JobInfo>),
+    /// Task was too busy to answer.
+    Busy,
 }
 
 #[derive(Debug)]
 pub enum FailReason {
     MaxRetryReached,
+    FailedPermanently,
+    Misfired,
+}
+
+impl From<FailReason> for FailureReason {
+    fn from(reason: FailReason) -> Self {
+        match reason {
+            FailReason::MaxRetryReached => Self::MaxRetryReached,
+            FailReason::FailedPermanently => Self::FailedPermanently,
+            FailReason::Misfired => Self::Misfired,
+        }
+    }
 }
 
 pub struct TaskHandle(mpsc::Sender<TaskCtl>);
@@ -38,16 +83,37 @@ impl std::ops::DerefMut for TaskHandle {
 #[derive(Debug, Clone)]
 pub struct Task {
     max_retry: u8,
+    failure_history: usize,
+    backoff: Backoff,
     to_exec: mpsc::Sender<ExecutorCtl>,
+    storage: Arc<dyn Storage>,
+    events: Events,
+    metrics: Metrics,
 }
 
 impl Task {
-    pub fn new(to_exec: mpsc::Sender<ExecutorCtl>, max_retry: u8) -> Self {
-        Self { to_exec, max_retry }
+    pub fn new(
+        to_exec: mpsc::Sender<ExecutorCtl>,
+        max_retry: u8,
+        failure_history: usize,
+        backoff: Backoff,
+        storage: Arc<dyn Storage>,
+        events: Events,
+        metrics: Metrics,
+    ) -> Self {
+        Self {
+            to_exec,
+            max_retry,
+            failure_history,
+            backoff,
+            storage,
+            events,
+            metrics,
+        }
     }
 
     #[instrument(name = "task")]
-    pub fn spawn(&self, mut job: Job) -> TaskHandle {
+    pub fn spawn(&self, mut job: Job, mut try_count: u8) -> TaskHandle {
         let (tx, mut rx) = mpsc::channel(10);
 
         info!(
@@ -56,13 +122,32 @@ impl Task {
             job_id = %(&job.id)
         );
 
-        let max_retry = self.max_retry;
+        // larger limits are rejected on enqueue
+        let max_retry = match job.retry.as_ref().map(|r| r.max_attempts) {
+            Some(n) if n > 0 => u8::try_from(n).unwrap_or(u8::MAX),
+            _ => self.max_retry,
+        };
+        let failure_history = self.failure_history;
+        let backoff = self.backoff;
         let mut to_exec = self.to_exec.clone();
+        let storage = self.storage.clone();
+        let events = self.events.clone();
+        let labels = [job.name.as_str()];
+        let succeeded = self.metrics.succeeded.with_label_values(&labels);
+        let failed = self.metrics.failed.with_label_values(&labels);
+        let time_in_queue = self.metrics.time_in_queue.with_label_values(&labels);
+        let execution_time = self.metrics.execution_time.with_label_values(&labels);
         let task = async move {
-            let mut try_count: u8 = 0;
+            let mut info = JobInfo {
+                try_count: try_count as u32,
+                ..JobInfo::default()
+            };
+            let mut watchers = Vec::new();
+            // set when last attempt failed (or its worker was lost) and job goes again
+            let mut retrying = false;
 
-            let res = loop {
-                if try_count == max_retry {
+            let res = 'task: loop {
+                if try_count >= max_retry {
                     warn!(
                         message = "reached max retry",
                         job_name = %(&job.name),
@@ -71,25 +156,96 @@ impl Task {
                     break Err(FailReason::MaxRetryReached);
                 };
 
+                if let Some(late) = lateness(&job) {
+                    match job.misfire.as_ref().map(|m| m.action()) {
+                        Some(MisfireAction::Skip) => {
+                            info!(message = "skipped late job", job_id = %job.id, ?late);
+                            let mut result = job_result(&job, JobStatus::Failed);
+                            result.error_message =
+                                format!("skipped, missed its schedule by {:?}", late);
+                            break Ok(Some(result));
+                        }
+                        Some(MisfireAction::DeadLetter) => {
+                            let failure = Failure {
+                                message: format!("missed its schedule by {:?}", late),
+                                ..Failure::default()
+                            };
+                            record_failure(&mut info, failure, failure_history, &job, &events);
+                            failed.inc();
+                            break Err(FailReason::Misfired);
+                        }
+                        Some(MisfireAction::Run) | None => {}
+                    }
+                }
+
                 let wait_dur = calc_wait_dur(&job.execution_time);
+                info.set_state(JobState::Scheduled);
+                info.next_attempt_at = SystemTime::now().checked_add(wait_dur).map(Into::into);
+                info.worker_id.clear();
+                info.reserved_until = None;
+                let kind = if retrying {
+                    Some(EventKind::RetryScheduled)
+                } else if wait_dur > Duration::default() {
+                    Some(EventKind::Scheduled)
+                } else {
+                    None
+                };
+                if let Some(kind) = kind {
+                    events.publish(Event {
+                        next_attempt_at: info.next_attempt_at.clone(),
+                        ..Event::job(kind, &job)
+                    });
+                }
+                retrying = false;
                 let mut delay = delay_for(wait_dur);
                 loop {
                     tokio::select! {
                         _ = &mut delay => break,
-                        Some(_) = rx.recv() => {
-                           // hadle status queries in the future
+                        Some(ctl) = rx.recv() => match ctl {
+                            TaskCtl::Report(tx) => report(tx, &info, &job).await,
+                            TaskCtl::Watch(tx) => watch(tx, &info, &job, &mut watchers),
+                            TaskCtl::Cancel => break 'task Ok(None),
+                            _ => {}
                         }
                     }
                 }
 
+                info.set_state(JobState::Waiting);
+                let ready_at = Instant::now();
+                info.next_attempt_at = None;
                 let (worker_tx, mut worker_rx) = mpsc::channel(1);
                 to_exec
-                    .send(ExecutorCtl::ProvideWorker(worker_tx))
+                    .send(ExecutorCtl::ProvideWorker(
+                        job.id.clone(),
+                        job.priority,
+                        affinity_key(&job),
+                        worker_tx,
+                    ))
                     .await
                     .unwrap();
-                let mut w = worker_rx.recv().await.unwrap();
+                let mut w = loop {
+                    tokio::select! {
+                        // request is dropped by executor once job is cancelled
+                        Some(w) = worker_rx.recv() => break w,
+                        Some(ctl) = rx.recv() => match ctl {
+                            TaskCtl::Report(tx) => report(tx, &info, &job).await,
+                            TaskCtl::Watch(tx) => watch(tx, &info, &job, &mut watchers),
+                            TaskCtl::Cancel => {
+                                // worker might be on its way already with a slot taken
+                                // for us, late ones get released by the feeder
+                                worker_rx.close();
+                                while let Ok(w) = worker_rx.try_recv() {
+                                    w.release();
+                                }
+                                break 'task Ok(None);
+                            }
+                            _ => {}
+                        }
+                    }
+                };
 
                 if w.work(job.clone()).await.is_err() {
+                    w.release();
                     to_exec.send(ExecutorCtl::RemoveWorker(w.id)).await.unwrap();
                     job.execution_time = Some(ExecutionTime::Immediate(()));
                     continue;
@@ -97,38 +253,190 @@ impl Task {
                 // inc try_count only after job was successfully sent to a worker
                 // worker unavailability doesn't count as job failure
                 try_count += 1;
+                let dispatched_at = Instant::now();
+                time_in_queue.observe((dispatched_at - ready_at).as_secs_f64());
+                info.try_count = try_count as u32;
+                events.publish(Event {
+                    worker_id: w.id.clone(),
+                    ..Event::job(EventKind::Dispatched, &job)
+                });
+                // schedule is served, attempts after reservation expiry shouldn't wait for it
+                if let Some(ExecutionTime::Scheduled(_)) = job.execution_time {
+                    job.execution_time = Some(ExecutionTime::Immediate(()));
+                }
 
                 let reservation_time = match &job.reservation_time {
                     Some(t) => t,
                     None => {
                         // if job has no reservation time we won't wait for it's status
-                        // and assume it succeeded
-                        break Ok(());
+                        // and assume it succeeded without output
+                        events.publish(Event::job(EventKind::Succeeded, &job));
+                        succeeded.inc();
+                        w.release();
+                        to_exec
+                            .send(ExecutorCtl::ReleaseWorker(job.id.clone()))
+                            .await
+                            .unwrap();
+                        break Ok(Some(job_result(&job, JobStatus::Succeeded)));
                     }
                 };
+                to_exec
+                    .send(ExecutorCtl::ReserveWorker(job.id.clone(), w.id.clone()))
+                    .await
+                    .unwrap();
 
-                let dur = Duration::from_secs(reservation_time.seconds as u64);
+                // negative durations are rejected on enqueue
+                let dur = Duration::try_from(reservation_time.clone()).unwrap_or_default();
+                info.set_state(JobState::Reserved);
+                info.worker_id = w.id.clone();
+                info.reserved_until = SystemTime::now().checked_add(dur).map(Into::into);
                 let mut delay = delay_for(dur);
-                tokio::select! {
-                    _ = &mut delay => {},
-                    Some(ctl) = rx.recv() => {
-                        match ctl {
-                            TaskCtl::Retry => expand_delay(&mut job, try_count),
-                            TaskCtl::Terminate => break Ok(()),
+                // set once there is nothing more to do with the job
+                let mut done = None;
+                loop {
+                    tokio::select! {
+                        _ = &mut delay => {
+                            let failure = Failure {
+                                message: "reservation expired".to_owned(),
+                                ..Failure::default()
+                            };
+                            record_failure(&mut info, failure, failure_history, &job, &events);
+                            failed.inc();
+                            break;
+                        },
+                        Some(ctl) = rx.recv() => {
+                            match ctl {
+                                TaskCtl::Retry(mut failure) => {
+                                    execution_time.observe(dispatched_at.elapsed().as_secs_f64());
+                                    expand_delay(&mut job, try_count, &backoff);
+                                    if failure.message.is_empty() {
+                                        failure.message = "worker reported failure".to_owned();
+                                    }
+                                    record_failure(&mut info, failure, failure_history, &job, &events);
+                                    failed.inc();
+                                }
+                                TaskCtl::GiveUp(failure) => {
+                                    execution_time.observe(dispatched_at.elapsed().as_secs_f64());
+                                    record_failure(&mut info, failure, failure_history, &job, &events);
+                                    failed.inc();
+                                    done = Some(Err(FailReason::FailedPermanently));
+                                }
+                                TaskCtl::Terminate(res) => {
+                                    execution_time.observe(dispatched_at.elapsed().as_secs_f64());
+                                    events.publish(Event::job(EventKind::Succeeded, &job));
+                                    succeeded.inc();
+                                    done = Some(Ok(Some(res)));
+                                }
+                                TaskCtl::WorkerLost(worker_id) => {
+                                    // notice might come late, after another worker took over
+                                    if worker_id != w.id {
+                                        continue;
+                                    }
+                                    // counts as an attempt, otherwise a job that keeps
+                                    // crashing its workers would be retried forever
+                                    let failure = Failure {
+                                        message: "worker lost".to_owned(),
+                                        ..Failure::default()
+                                    };
+                                    record_failure(&mut info, failure, failure_history, &job, &events);
+                                    failed.inc();
+                                    job.execution_time = Some(ExecutionTime::Immediate(()));
+                                }
+                                TaskCtl::Report(tx) => {
+                                    report(tx, &info, &job).await;
+                                    continue;
+                                }
+                                TaskCtl::Watch(tx) => {
+                                    watch(tx, &info, &job, &mut watchers);
+                                    continue;
+                                }
+                                TaskCtl::Progress(worker_id, mut progress) => {
+                                    if worker_id == w.id {
+                                        progress.reported_at = Some(SystemTime::now().into());
+                                        if !progress.checkpoint.is_empty() {
+                                            job.checkpoint = progress.checkpoint.clone();
+                                        }
+                                        // slow watchers miss some updates rather than
+                                        // hold the task up
+                                        watchers.retain_mut(|tx| {
+                                            !matches!(
+                                                tx.try_send(progress.clone()),
+                                                Err(TrySendError::Closed(_))
+                                            )
+                                        });
+                                        info.progress = Some(progress);
+                                    }
+                                    continue;
+                                }
+                                TaskCtl::Touch(worker_id, extend_by) => {
+                                    // other workers might still be sending touches after
+                                    // their reservation expired
+                                    if worker_id == w.id {
+                                        let extend_by = extend_by.unwrap_or(dur);
+                                        // bounded by manager, still not worth a panic
+                                        let deadline = Instant::now().checked_add(extend_by);
+                                        if let Some(deadline) = deadline {
+                                            delay.reset(deadline);
+                                            info.reserved_until = SystemTime::now()
+                                                .checked_add(extend_by)
+                                                .map(Into::into);
+                                        }
+                                    }
+                                    continue;
+                                }
+                                TaskCtl::Cancel => {
+                                    w.cancel(job.id.clone());
+                                    done = Some(Ok(None));
+                                }
+                            }
+                            break;
                         }
                     }
                 }
+                // reservation is over one way or another so worker can take another job
+                w.release();
+                to_exec
+                    .send(ExecutorCtl::ReleaseWorker(job.id.clone()))
+                    .await
+                    .unwrap();
+                if let Some(res) = done {
+                    break res;
+                }
+                // failure is logged by storage, job goes on anyway
+                let _ = storage.reschedule(&job, try_count).await;
+                retrying = true;
             };
 
             match res {
-                Ok(_) => {
+                Ok(result) => {
+                    let _ = storage.finish(&job.id).await;
                     info!(message = "finished", job_name = %job.name, job_id = %job.id);
+
+                    to_exec
+                        .send(ExecutorCtl::HandleFinishedJob(job, result))
+                        .await
+                        .unwrap();
                 }
                 Err(reason) => {
-                    warn!(message = "failed", job_name = %job.name, job_id = %job.id);
+                    warn!(message = "failed", job_name = %job.name, job_id = %job.id, ?reason);
 
+                    let mut dead = DeadJob {
+                        job: Some(job),
+                        died_at: Some(SystemTime::now().into()),
+                        last_error: info.last_failure,
+                        try_count: info.try_count,
+                        failures: info.failures,
+                        ..DeadJob::default()
+                    };
+                    dead.set_reason(reason.into());
+                    events.publish(Event {
+                        message: dead.last_error.clone(),
+                        ..Event::job(EventKind::Died, dead.job.as_ref().unwrap())
+                    });
+                    // failure is logged by storage, job is still listed until restart
+                    let _ = storage.bury(dead.clone()).await;
                     to_exec
-                        .send(ExecutorCtl::HandleDyingJob(job, reason))
+                        .send(ExecutorCtl::HandleDyingJob(dead))
                         .await
                         .unwrap();
                 }
@@ -140,32 +448,110 @@ impl Task {
     }
 }
 
-fn expand_delay(job: &mut Job, try_count: u8) {
-    // 15 + count ^ 4 + (rand(30) * (count + 1))
-    // see https://github.com/contribsys/faktory/wiki/Job-Errors
+async fn report(mut tx: mpsc::Sender<Report>, info: &JobInfo, job: &Job) {
+    let mut info = info.clone();
+    info.job = Some(job.clone());
+    let _ = tx.send(Report::Job(Box::new(info))).await;
+}
+
+/// Outcome of a job that didn't come from a worker.
+fn job_result(job: &Job, status: JobStatus) -> JobResult {
+    let mut result = JobResult {
+        job_id: job.id.clone(),
+        job_name: job.name.clone(),
+        ..JobResult::default()
+    };
+    result.set_status(status);
+    result
+}
+
+/// Keeps `failure` as the latest of at most `history` failures of the job.
+fn record_failure(
+    info: &mut JobInfo,
+    mut failure: Failure,
+    history: usize,
+    job: &Job,
+    events: &Events,
+) {
+    failure.attempt = info.try_count;
+    failure.failed_at = Some(SystemTime::now().into());
+    info.last_failure = failure.message.clone();
+    events.publish(Event {
+        message: failure.message.clone(),
+        ..Event::job(EventKind::Failed, job)
+    });
+    info.failures.push(failure);
+    if info.failures.len() > history {
+        let excess = info.failures.len() - history;
+        info.failures.drain(..excess);
+    }
+}
+
+fn watch(
+    mut tx: mpsc::Sender<Progress>,
+    info: &JobInfo,
+    job: &Job,
+    watchers: &mut Vec<mpsc::Sender<Progress>>,
+) {
+    let current = info.progress.clone().unwrap_or_else(|| Progress {
+        job_id: job.id.clone(),
+        job_name: job.name.clone(),
+        ..Progress::default()
+    });
+    if tx.try_send(current).is_ok() {
+        watchers.push(tx);
+    }
+}
+
+fn affinity_key(job: &Job) -> String {
+    if job.affinity_key.is_empty() {
+        job.id.clone()
+    } else {
+        job.affinity_key.clone()
+    }
+}
 
-    let r: u8 = rand::thread_rng().gen_range(0, 30);
-    let seconds = 15 + (try_count ^ 4) + (r * (try_count + 1));
-    let delay = prost_types::Duration {
-        seconds: seconds as i64,
-        nanos: 0,
+fn expand_delay(job: &mut Job, try_count: u8, backoff: &Backoff) {
+    let backoff = match &job.retry {
+        Some(policy) => Backoff::from_policy(policy, backoff),
+        None => *backoff,
     };
-    job.execution_time = Some(ExecutionTime::Delayed(delay));
+    let delay = backoff.delay(try_count as u32, &mut rand::thread_rng());
+    job.execution_time = Some(ExecutionTime::Delayed(delay.into()));
+}
+
+/// How far past its tolerance a scheduled job is.
+fn lateness(job: &Job) -> Option<Duration> {
+    let scheduled = match &job.execution_time {
+        Some(ExecutionTime::Scheduled(timestamp)) => {
+            SystemTime::try_from(timestamp.clone()).ok()?
+        }
+        _ => return None,
+    };
+    let tolerance = job
+        .misfire
+        .as_ref()
+        .and_then(|m| m.tolerance.clone())
+        .and_then(|t| Duration::try_from(t).ok())
+        .unwrap_or_default();
+    let late = SystemTime::now().duration_since(scheduled).ok()?;
+    if late > tolerance {
+        Some(late)
+    } else {
+        None
+    }
 }
 
 fn calc_wait_dur(exec_time: &Option<ExecutionTime>) -> Duration {
     match exec_time {
         Some(ex_time) => match ex_time {
             ExecutionTime::Immediate(_) => Duration::new(0, 0),
-            ExecutionTime::Scheduled(timestamp) => {
-                let duration_since_epoch =
-                    Duration::new(timestamp.seconds as u64, timestamp.nanos as u32);
-                let system_duration_since_epoch = SystemTime::now()
-                    .duration_since(SystemTime::UNIX_EPOCH)
-                    .unwrap();
-                duration_since_epoch - system_duration_since_epoch
-            }
-            ExecutionTime::Delayed(dur) => Duration::new(dur.seconds as u64, dur.nanos as u32),
+            // time in the past means right away
+            ExecutionTime::Scheduled(timestamp) => SystemTime::try_from(timestamp.clone())
+                .ok()
+                .and_then(|t| t.duration_since(SystemTime::now()).ok())
+                .unwrap_or_default(),
+            ExecutionTime::Delayed(dur) => Duration::try_from(dur.clone()).unwrap_or_default(),
         },
         None => Duration::new(0, 0),
     }
@@ -1,22 +1,91 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 use tokio::sync::mpsc;
 use tokio::sync::mpsc::error::SendError;
 use tonic::Status;
 
-use crate::pb::Job;
+use crate::pb::server_message::Message;
+use crate::pb::{Job, JobId, ServerMessage};
 pub type WorkerId = String;
 
 #[derive(Debug, Clone)]
 pub struct Worker {
     pub id: WorkerId,
-    inner: mpsc::Sender<Result<Job, Status>>,
+    inner: mpsc::Sender<Result<ServerMessage, Status>>,
+    /// `0` means there is no limit.
+    max_in_flight: usize,
+    /// Shared between clones given to every executor this worker joined.
+    in_flight: Arc<AtomicUsize>,
 }
 
 impl Worker {
-    pub fn new(id: WorkerId, inner: mpsc::Sender<Result<Job, Status>>) -> Self {
-        Self { id, inner }
+    pub fn new(
+        id: WorkerId,
+        inner: mpsc::Sender<Result<ServerMessage, Status>>,
+        max_in_flight: usize,
+    ) -> Self {
+        Self {
+            id,
+            inner,
+            max_in_flight,
+            in_flight: Arc::new(AtomicUsize::new(0)),
+        }
     }
 
-    pub async fn work(&mut self, j: Job) -> Result<(), SendError<Result<Job, Status>>> {
-        self.inner.send(Ok(j)).await
+    pub async fn work(&mut self, j: Job) -> Result<(), SendError<Result<ServerMessage, Status>>> {
+        self.send(Message::Job(j)).await
+    }
+
+    /// Lets worker know it can stop working on a job. Best effort, the notice is
+    /// dropped if worker's stream is full.
+    pub fn cancel(&mut self, job_id: String) {
+        let msg = ServerMessage {
+            message: Some(Message::Cancelled(JobId { id: job_id })),
+        };
+        let _ = self.inner.try_send(Ok(msg));
+    }
+
+    async fn send(&mut self, msg: Message) -> Result<(), SendError<Result<ServerMessage, Status>>> {
+        let msg = ServerMessage { message: Some(msg) };
+        self.inner.send(Ok(msg)).await
+    }
+
+    /// Ends worker's job stream with `status`, it's up to the worker to notice.
+    /// Worker that doesn't read its stream won't get the status but the stream
+    /// ends anyway once every handle to it is dropped.
+    pub fn disconnect(&mut self, status: Status) {
+        let _ = self.inner.try_send(Err(status));
+    }
+
+    pub fn max_in_flight(&self) -> usize {
+        self.max_in_flight
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::SeqCst)
+    }
+
+    pub fn has_capacity(&self) -> bool {
+        self.max_in_flight == 0 || self.in_flight.load(Ordering::SeqCst) < self.max_in_flight
+    }
+
+    /// Takes up one of worker's in-flight slots, returns `false` if there are none left.
+    pub fn try_reserve(&self) -> bool {
+        let max = self.max_in_flight;
+        self.in_flight
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+                if max == 0 || n < max {
+                    Some(n + 1)
+                } else {
+                    None
+                }
+            })
+            .is_ok()
+    }
+
+    pub fn release(&self) {
+        let _ = self
+            .in_flight
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1));
     }
 }